@@ -1,10 +1,19 @@
 use std::{collections::HashSet, fmt::Display};
 
+use crate::polygon;
+
 pub fn solve() {
     let input = std::fs::read_to_string("input/day10.txt").unwrap();
     println!("# Day 10");
     println!("Part 1: {}", part1(&input));
     println!("Part 2: {}", part2(&input));
+    if std::env::args().any(|arg| arg == "--render") {
+        let charset = match std::env::args().any(|arg| arg == "--ascii") {
+            true => Charset::Ascii,
+            false => Charset::Unicode,
+        };
+        print!("{}", Map::from(input.as_str()).render(charset).unwrap());
+    }
 }
 
 const START: char = 'S';
@@ -19,13 +28,116 @@ enum Direction {
     East,
 }
 
-#[derive(Debug)]
+impl Direction {
+    const ALL: [Direction; 4] = [
+        Direction::North,
+        Direction::West,
+        Direction::South,
+        Direction::East,
+    ];
+
+    fn opposite(&self) -> Direction {
+        match self {
+            Direction::North => Direction::South,
+            Direction::West => Direction::East,
+            Direction::South => Direction::North,
+            Direction::East => Direction::West,
+        }
+    }
+
+    fn step(&self, pos: &Position) -> Position {
+        match self {
+            Direction::North => (pos.0, pos.1 - 1),
+            Direction::West => (pos.0 - 1, pos.1),
+            Direction::South => (pos.0, pos.1 + 1),
+            Direction::East => (pos.0 + 1, pos.1),
+        }
+    }
+}
+
+/// The two sides a pipe tile opens onto, or `None` for ground and unknown tiles.
+fn connections(tile: char) -> Option<[Direction; 2]> {
+    match tile {
+        '|' => Some([Direction::North, Direction::South]),
+        '-' => Some([Direction::West, Direction::East]),
+        'L' => Some([Direction::North, Direction::East]),
+        'J' => Some([Direction::North, Direction::West]),
+        '7' => Some([Direction::West, Direction::South]),
+        'F' => Some([Direction::South, Direction::East]),
+        _ => None,
+    }
+}
+
+/// The pipe tile that opens onto both directions.
+fn shape(a: Direction, b: Direction) -> Option<char> {
+    ['|', '-', 'L', 'J', '7', 'F']
+        .into_iter()
+        .find(|&tile| connections(tile).is_some_and(|c| c.contains(&a) && c.contains(&b)))
+}
+
+#[derive(Debug, PartialEq)]
+enum MazeError {
+    NoStart,
+    OffMap {
+        position: Position,
+    },
+    Disconnected {
+        position: Position,
+        tile: char,
+        direction: Direction,
+    },
+    NoLoop,
+}
+
+impl Display for MazeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MazeError::NoStart => write!(f, "no start tile {}", START),
+            MazeError::OffMap { position } => write!(f, "loop leaves the map at {:?}", position),
+            MazeError::Disconnected {
+                position,
+                tile,
+                direction,
+            } => write!(
+                f,
+                "cannot enter {} at {:?} moving {:?}",
+                tile, position, direction
+            ),
+            MazeError::NoLoop => write!(f, "no loop runs through the start tile"),
+        }
+    }
+}
+
+/// A pipe maze. The start tile is replaced by the pipe shape that closes the
+/// loop through it, so `start` is the only place that remembers it.
+#[derive(Debug, Clone)]
 struct Map {
     map: Vec<Vec<char>>,
     start: Position,
 }
 
 impl Map {
+    fn parse(input: &str) -> Result<Self, MazeError> {
+        let map = input
+            .trim()
+            .lines()
+            .map(|line| line.chars().collect::<Vec<char>>())
+            .collect::<Vec<Vec<char>>>();
+
+        let (start_y, start_line) = map
+            .iter()
+            .enumerate()
+            .find(|(_, line)| line.contains(&START))
+            .ok_or(MazeError::NoStart)?;
+        let start_x = start_line.iter().position(|&c| c == START).unwrap();
+        let start = (start_x as isize, start_y as isize);
+
+        let mut map = Self { map, start };
+        let tile = map.resolve_start()?;
+        map.map[start_y][start_x] = tile;
+        Ok(map)
+    }
+
     fn get(&self, pos: &Position) -> Option<&char> {
         let (x, y) = pos;
         if *x < 0 || *y < 0 {
@@ -36,8 +148,23 @@ impl Map {
             .and_then(|line| line.get(*x as usize))
     }
 
-    fn replace_non_loop_chars(&mut self) {
-        let loop_positions: HashSet<Position> = self.get_loop_positions().into_iter().collect();
+    /// Tries each neighbour that connects back to the start until one leads
+    /// round a loop, and returns the shape joining its two ends.
+    fn resolve_start(&self) -> Result<char, MazeError> {
+        let mut error = MazeError::NoLoop;
+        for direction in self.start_directions() {
+            match self.walk(direction) {
+                Ok((_, last)) => {
+                    return shape(direction, last.opposite()).ok_or(MazeError::NoLoop);
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    fn replace_non_loop_chars(&mut self) -> Result<(), MazeError> {
+        let loop_positions: HashSet<Position> = self.get_loop_positions()?.into_iter().collect();
 
         for y in 0..self.map.len() {
             for x in 0..self.map[y].len() {
@@ -46,84 +173,171 @@ impl Map {
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Marks the tiles enclosed by the loop, scanning each row and flipping
+    /// at every loop tile with a pipe going north.
+    fn inside_tiles(&self) -> Result<Vec<Vec<bool>>, MazeError> {
+        let mut map = self.clone();
+        map.replace_non_loop_chars()?;
+        let mut result = Vec::with_capacity(map.map.len());
+        for row in map.map {
+            let mut in_loop = false;
+            let mut inside = Vec::with_capacity(row.len());
+            for char in row {
+                match char {
+                    '|' | 'J' | 'L' => in_loop = !in_loop,
+                    '.' => {
+                        inside.push(in_loop);
+                        continue;
+                    }
+                    _ => (),
+                }
+                inside.push(false);
+            }
+            result.push(inside);
+        }
+        Ok(result)
+    }
+
+    /// Draws the loop on its own with `I` and `O` on every other tile. Tiles
+    /// that held stray pipes are dimmed, the start is `S` and the tile
+    /// farthest along the loop from it is `*`.
+    fn render(&self, charset: Charset) -> Result<String, MazeError> {
+        let positions = self.get_loop_positions()?;
+        let farthest = positions[positions.len() / 2 - 1];
+        let on_loop: HashSet<Position> = positions.into_iter().collect();
+        let inside = self.inside_tiles()?;
+        let mut result = String::new();
+        for (y, row) in self.map.iter().enumerate() {
+            for (x, &tile) in row.iter().enumerate() {
+                let position = (x as isize, y as isize);
+                if position == self.start {
+                    result.push(START);
+                } else if position == farthest {
+                    result.push(charset.farthest());
+                } else if on_loop.contains(&position) {
+                    result.push(charset.pipe(tile));
+                } else {
+                    let mark = if inside[y][x] { 'I' } else { 'O' };
+                    match connections(tile) {
+                        Some(_) => result.push_str(&charset.dim(mark)),
+                        None => result.push(mark),
+                    }
+                }
+            }
+            result.push('\n');
+        }
+        Ok(result)
+    }
+
+    fn get_loop_positions(&self) -> Result<Vec<Position>, MazeError> {
+        let (x, y) = self.start;
+        let tile = self.map[y as usize][x as usize];
+        let direction = connections(tile).ok_or(MazeError::NoLoop)?[0];
+        self.walk(direction).map(|(positions, _)| positions)
     }
 
-    fn get_loop_positions(&self) -> Vec<Position> {
-        let mut direction = self.start_directions()[0];
+    /// Follows the pipes from the start, leaving in `direction`, until it gets
+    /// back. Returns the tiles visited, ending with the start, and the
+    /// direction it was moving in when it arrived.
+    fn walk(&self, mut direction: Direction) -> Result<(Vec<Position>, Direction), MazeError> {
         let mut position = self.start;
         let mut result = vec![];
 
         loop {
-            let (next_pos, next_dir) = self.traverse(&position, &direction);
+            let (next_pos, next_dir) = self.traverse(&position, &direction)?;
             result.push(next_pos);
+            if next_pos == self.start {
+                return Ok((result, direction));
+            }
             position = next_pos;
             direction = next_dir;
-            if self.get(&next_pos) == Some(&START) {
-                break;
-            }
         }
-        result
     }
 
-    fn traverse(&self, pos: &Position, direction: &Direction) -> (Position, Direction) {
-        let next_pos = match direction {
-            Direction::North => (pos.0, pos.1 - 1),
-            Direction::West => (pos.0 - 1, pos.1),
-            Direction::South => (pos.0, pos.1 + 1),
-            Direction::East => (pos.0 + 1, pos.1),
-        };
+    fn traverse(
+        &self,
+        pos: &Position,
+        direction: &Direction,
+    ) -> Result<(Position, Direction), MazeError> {
+        let next_pos = direction.step(pos);
+        if next_pos == self.start {
+            return Ok((next_pos, *direction));
+        }
 
         match self.get(&next_pos) {
-            Some(curr) => (next_pos, self.char_direction(curr, direction)),
-            None => panic!("Invalid position: {:?}", pos),
-        }
-    }
-    fn char_direction(&self, char: &char, dir: &Direction) -> Direction {
-        match char {
-            'F' => match dir {
-                Direction::North => Direction::East,
-                Direction::West => Direction::South,
-                _ => panic!("Invalid movement: {}, {:?}", char, dir),
-            },
-            '7' => match dir {
-                Direction::North => Direction::West,
-                Direction::East => Direction::South,
-                _ => panic!("Invalid movement: {}, {:?}", char, dir),
-            },
-            'L' => match dir {
-                Direction::South => Direction::East,
-                Direction::West => Direction::North,
-                _ => panic!("Invalid movement: {}, {:?}", char, dir),
-            },
-            'J' => match dir {
-                Direction::South => Direction::West,
-                Direction::East => Direction::North,
-                _ => panic!("Invalid movement: {}, {:?}", char, dir),
-            },
-            _ => *dir,
+            Some(curr) => Ok((next_pos, self.char_direction(&next_pos, curr, direction)?)),
+            None => Err(MazeError::OffMap { position: next_pos }),
         }
     }
 
-    fn start_directions(&self) -> Vec<Direction> {
-        let mut result = Vec::new();
-        let (x, y) = self.start;
-        match self.get(&(x - 1, y)) {
-            Some('-') | Some('F') | Some('L') => result.push(Direction::West),
-            _ => (),
+    fn char_direction(
+        &self,
+        pos: &Position,
+        char: &char,
+        dir: &Direction,
+    ) -> Result<Direction, MazeError> {
+        let from = dir.opposite();
+        match connections(*char) {
+            Some([a, b]) if a == from => Ok(b),
+            Some([a, b]) if b == from => Ok(a),
+            _ => Err(MazeError::Disconnected {
+                position: *pos,
+                tile: *char,
+                direction: *dir,
+            }),
         }
-        match self.get(&(x + 1, y)) {
-            Some('-') | Some('7') | Some('J') => result.push(Direction::East),
-            _ => (),
+    }
+
+    /// Directions from the start whose neighbouring pipe opens back onto it.
+    fn start_directions(&self) -> Vec<Direction> {
+        Direction::ALL
+            .into_iter()
+            .filter(|direction| {
+                self.get(&direction.step(&self.start))
+                    .and_then(|&tile| connections(tile))
+                    .is_some_and(|c| c.contains(&direction.opposite()))
+            })
+            .collect()
+    }
+}
+
+/// Box-drawing characters and ANSI dimming, or plain ASCII for terminals
+/// that cannot show them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Charset {
+    Unicode,
+    Ascii,
+}
+
+impl Charset {
+    fn pipe(&self, tile: char) -> char {
+        match (self, tile) {
+            (Charset::Unicode, '|') => '│',
+            (Charset::Unicode, '-') => '─',
+            (Charset::Unicode, 'L') => '└',
+            (Charset::Unicode, 'J') => '┘',
+            (Charset::Unicode, '7') => '┐',
+            (Charset::Unicode, 'F') => '┌',
+            (Charset::Ascii, 'L' | 'J' | '7' | 'F') => '+',
+            _ => tile,
         }
-        match self.get(&(x, y - 1)) {
-            Some('|') | Some('F') | Some('7') => result.push(Direction::North),
-            _ => (),
+    }
+
+    fn farthest(&self) -> char {
+        match self {
+            Charset::Unicode => '●',
+            Charset::Ascii => '*',
         }
-        match self.get(&(x, y + 1)) {
-            Some('|') | Some('L') | Some('J') => result.push(Direction::South),
-            _ => (),
+    }
+
+    fn dim(&self, mark: char) -> String {
+        match self {
+            Charset::Unicode => format!("\x1b[2m{}\x1b[0m", mark),
+            Charset::Ascii => mark.to_ascii_lowercase().to_string(),
         }
-        result
     }
 }
 
@@ -142,55 +356,27 @@ impl Display for Map {
 
 impl From<&str> for Map {
     fn from(input: &str) -> Self {
-        let map = input
-            .trim()
-            .lines()
-            .map(|line| line.chars().collect::<Vec<char>>())
-            .collect::<Vec<Vec<char>>>();
-
-        let (start_y, start_line) = map
-            .iter()
-            .enumerate()
-            .find(|(_, line)| line.contains(&START))
-            .unwrap();
-        let start_x = start_line.iter().position(|&c| c == START).unwrap();
-        let start = (start_x as isize, start_y as isize);
-
-        Self { map, start }
+        Self::parse(input).unwrap_or_else(|e| panic!("{}", e))
     }
 }
 
 fn part1(input: &str) -> i32 {
     let map = Map::from(input);
-    map.get_loop_positions().len() as i32 / 2
+    map.get_loop_positions().unwrap().len() as i32 / 2
 }
 
 fn part2(input: &str) -> i32 {
-    let mut map = Map::from(input);
-    map.replace_non_loop_chars();
-    let mut count = 0;
-    let include_start = map.start_directions().contains(&Direction::North);
-    for row in map.map {
-        let mut in_loop = false;
-        for char in row {
-            match char {
-                '|' | 'J' | 'L' => in_loop = !in_loop,
-                'S' => {
-                    if include_start {
-                        in_loop = !in_loop
-                    }
-                }
-                '.' => {
-                    if in_loop {
-                        count += 1
-                    }
-                }
-                _ => (),
-            }
-        }
-    }
+    let map = Map::from(input);
+    let inside = map.inside_tiles().unwrap();
+    inside.iter().flatten().filter(|&&inside| inside).count() as i32
+}
 
-    count
+/// Counts the enclosed tiles with Pick's theorem instead of scanning rows:
+/// every loop tile is a lattice point on the boundary of the polygon.
+fn part2_polygon(input: &str) -> i32 {
+    let map = Map::from(input);
+    let positions = map.get_loop_positions().unwrap();
+    polygon::measure(&positions).unwrap().interior as i32
 }
 
 #[test]
@@ -203,6 +389,57 @@ SJ.L7
 LJ...
 ";
     assert_eq!(part1(input), 8);
+    let map = Map::from(input);
+    assert_eq!(map.start, (0, 2));
+    assert_eq!(map.map[2][0], 'F');
+
+    // Only the start's east and south neighbours lead back round to it.
+    let decoys = "
+.|...
+-S-7.
+.|.|.
+.L-J.
+";
+    assert_eq!(Map::from(decoys).map[1][1], 'F');
+    assert_eq!(part1(decoys), 4);
+    assert_eq!(part2(decoys), 1);
+    assert_eq!(part2_polygon(decoys), 1);
+    let map = Map::from(decoys);
+    assert_eq!(
+        map.render(Charset::Ascii).unwrap(),
+        "\
+OoOOO
+oS-+O
+O|I|O
+O+-*O
+"
+    );
+    assert_eq!(
+        map.render(Charset::Unicode).unwrap(),
+        "\
+O\x1b[2mO\x1b[0mOOO
+\x1b[2mO\x1b[0mS─┐O
+O│I│O
+O└─●O
+"
+    );
+
+    assert_eq!(Map::parse("...").unwrap_err(), MazeError::NoStart);
+    assert_eq!(Map::parse(".S.").unwrap_err(), MazeError::NoLoop);
+    let error = Map::parse(".S-.").unwrap_err();
+    assert_eq!(
+        error,
+        MazeError::Disconnected {
+            position: (3, 0),
+            tile: '.',
+            direction: Direction::East
+        }
+    );
+    assert_eq!(error.to_string(), "cannot enter . at (3, 0) moving East");
+    assert_eq!(
+        Map::parse("S-").unwrap_err(),
+        MazeError::OffMap { position: (2, 0) }
+    );
 
     let input2 = "
 ..........
@@ -230,4 +467,7 @@ L7JLJL-JLJLJL--JLJ.L
 ";
     assert_eq!(part2(input2), 4);
     assert_eq!(part2(input3), 10);
+    for input in [input, input2, input3] {
+        assert_eq!(part2_polygon(input), part2(input));
+    }
 }
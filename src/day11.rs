@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 pub fn solve() {
     let input = std::fs::read_to_string("input/day11.txt").unwrap();
     println!("# Day 11");
@@ -18,6 +20,43 @@ impl Galaxy {
     }
 }
 
+/// How many lines each line of the image becomes. Empty columns use
+/// `horizontal` and empty rows `vertical`; lines holding a galaxy stay one
+/// line wide. A custom factor for a column or row index overrides both, and
+/// galaxies on a stretched line sit on its first copy.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Expansion {
+    horizontal: usize,
+    vertical: usize,
+    columns: HashMap<usize, usize>,
+    rows: HashMap<usize, usize>,
+}
+
+impl Expansion {
+    fn uniform(factor: usize) -> Self {
+        Self::new(factor, factor)
+    }
+
+    fn new(horizontal: usize, vertical: usize) -> Self {
+        Self {
+            horizontal,
+            vertical,
+            columns: HashMap::new(),
+            rows: HashMap::new(),
+        }
+    }
+
+    fn with_column(mut self, x: usize, factor: usize) -> Self {
+        self.columns.insert(x, factor);
+        self
+    }
+
+    fn with_row(mut self, y: usize, factor: usize) -> Self {
+        self.rows.insert(y, factor);
+        self
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct Universe {
     galaxies: Vec<Galaxy>,
@@ -26,28 +65,95 @@ struct Universe {
 }
 
 impl Universe {
-    fn expand_by(&mut self, n: usize) {
-        let factor = (1).max(n - 1);
-        let occupied_cols: Vec<usize> = self.galaxies.iter().map(|g| g.x).collect();
-        let empty_cols = (0..self.width).filter(|x| !occupied_cols.contains(x));
-        let occupied_rows: Vec<usize> = self.galaxies.iter().map(|g| g.y).collect();
-        let empty_rows = (0..self.height).filter(|y| !occupied_rows.contains(y));
+    fn expand_with(&mut self, expansion: &Expansion) {
+        let cols = line_offsets(
+            self.galaxies.iter().map(|g| g.x),
+            self.width,
+            expansion.horizontal,
+            &expansion.columns,
+        );
+        let rows = line_offsets(
+            self.galaxies.iter().map(|g| g.y),
+            self.height,
+            expansion.vertical,
+            &expansion.rows,
+        );
         for galaxy in self.galaxies.iter_mut() {
-            let shift_x = empty_cols.clone().filter(|x| *x < galaxy.x).count() * factor;
-            let shift_y = empty_rows.clone().filter(|y| *y < galaxy.y).count() * factor;
-
-            galaxy.x += shift_x;
-            galaxy.y += shift_y;
+            galaxy.x = cols[galaxy.x];
+            galaxy.y = rows[galaxy.y];
         }
+        self.width = cols[self.width];
+        self.height = rows[self.height];
+    }
+
+    /// Replaces every empty row and column with `n` of them.
+    fn expand_by(&mut self, n: usize) {
+        self.expand_with(&Expansion::uniform(n));
     }
 
     fn expand(&mut self) {
-        self.expand_by(1);
+        self.expand_by(2);
     }
 
     fn manhattan_distance(&self, a: &Galaxy, b: &Galaxy) -> i64 {
         (a.x as i64 - b.x as i64).abs() + (a.y as i64 - b.y as i64).abs()
     }
+
+    fn galaxy(&self, label: usize) -> Option<&Galaxy> {
+        self.galaxies.iter().find(|g| g.label == label)
+    }
+
+    /// Distance between the galaxies numbered `from` and `to`, counting
+    /// from 1 in reading order as the puzzle does.
+    fn distance(&self, from: usize, to: usize) -> Option<i64> {
+        Some(self.manhattan_distance(self.galaxy(from)?, self.galaxy(to)?))
+    }
+
+    /// Sum of the distances between every pair of galaxies. The x and y
+    /// parts are independent, so each axis is sorted and summed on its own.
+    fn distance_sum(&self) -> u128 {
+        axis_distance_sum(self.galaxies.iter().map(|g| g.x).collect())
+            + axis_distance_sum(self.galaxies.iter().map(|g| g.y).collect())
+    }
+}
+
+/// `result[i]` is where line `i` starts once every line before it has been
+/// stretched, with `result[len]` the new length.
+fn line_offsets(
+    occupied: impl Iterator<Item = usize>,
+    len: usize,
+    factor: usize,
+    custom: &HashMap<usize, usize>,
+) -> Vec<usize> {
+    let mut is_occupied = vec![false; len];
+    for line in occupied {
+        is_occupied[line] = true;
+    }
+    let mut result = Vec::with_capacity(len + 1);
+    let mut offset = 0;
+    for (line, occupied) in is_occupied.into_iter().enumerate() {
+        result.push(offset);
+        offset += match (custom.get(&line), occupied) {
+            (Some(&custom), _) => custom,
+            (None, true) => 1,
+            (None, false) => factor,
+        };
+    }
+    result.push(offset);
+    result
+}
+
+/// Sum of `|a - b|` over all pairs. Once sorted, the value at index `i` is
+/// larger than the `i` values before it by `i * value - (sum of those)`.
+fn axis_distance_sum(mut values: Vec<usize>) -> u128 {
+    values.sort_unstable();
+    let mut before = 0u128;
+    let mut total = 0u128;
+    for (i, &value) in values.iter().enumerate() {
+        total += i as u128 * value as u128 - before;
+        before += value as u128;
+    }
+    total
 }
 
 impl From<&str> for Universe {
@@ -79,33 +185,13 @@ fn part1(input: &str) -> i64 {
     let mut universe = Universe::from(input);
 
     universe.expand();
-    let combinations = universe.galaxies.iter().enumerate().flat_map(|(i, a)| {
-        universe
-            .galaxies
-            .iter()
-            .enumerate()
-            .filter(move |(j, _)| *j > i)
-            .map(move |(_, b)| (a, b))
-    });
-    combinations
-        .map(|(a, b)| universe.manhattan_distance(a, b))
-        .sum()
+    i64::try_from(universe.distance_sum()).expect("distance sum overflows i64")
 }
 
 fn part2(input: &str, expand_by: usize) -> i64 {
     let mut universe = Universe::from(input);
     universe.expand_by(expand_by);
-    let combinations = universe.galaxies.iter().enumerate().flat_map(|(i, a)| {
-        universe
-            .galaxies
-            .iter()
-            .enumerate()
-            .filter(move |(j, _)| j > &i)
-            .map(move |(_, b)| (a, b))
-    });
-    combinations
-        .map(|(a, b)| universe.manhattan_distance(a, b))
-        .sum()
+    i64::try_from(universe.distance_sum()).expect("distance sum overflows i64")
 }
 
 #[test]
@@ -125,4 +211,53 @@ fn test() {
     assert_eq!(part1(input), 374);
     assert_eq!(part2(input, 10), 1030);
     assert_eq!(part2(input, 100), 8410);
+
+    let mut universe = Universe::from(input);
+    universe.expand_by(10);
+    let pairwise: i64 = universe
+        .galaxies
+        .iter()
+        .enumerate()
+        .flat_map(|(i, a)| universe.galaxies[i + 1..].iter().map(move |b| (a, b)))
+        .map(|(a, b)| universe.manhattan_distance(a, b))
+        .sum();
+    assert_eq!(universe.distance_sum(), pairwise as u128);
+    assert_eq!(
+        line_offsets([1, 3].into_iter(), 5, 3, &HashMap::from([(1, 2)])),
+        vec![0, 3, 5, 8, 9, 12]
+    );
+
+    let mut universe = Universe::from(input);
+    universe.expand();
+    assert_eq!((universe.width, universe.height), (13, 12));
+    assert_eq!(universe.distance(5, 9), Some(9));
+    assert_eq!(universe.distance(1, 7), Some(15));
+    assert_eq!(universe.distance(3, 6), Some(17));
+    assert_eq!(universe.distance(8, 9), Some(5));
+    assert_eq!(universe.distance(9, 8), Some(5));
+    assert_eq!(universe.distance(1, 10), None);
+
+    // Galaxy 5 is at (1, 5) and galaxy 9 at (4, 9). Column 2 and row 7 are
+    // empty and lie between them.
+    let distance = |expansion: Expansion| {
+        let mut universe = Universe::from(input);
+        universe.expand_with(&expansion);
+        universe.distance(5, 9).unwrap()
+    };
+    assert_eq!(distance(Expansion::uniform(1)), 3 + 4);
+    assert_eq!(distance(Expansion::new(2, 1)), 4 + 4);
+    assert_eq!(distance(Expansion::new(1, 2)), 3 + 5);
+    assert_eq!(distance(Expansion::new(1, 1).with_row(7, 10)), 3 + 13);
+    assert_eq!(distance(Expansion::new(5, 1).with_column(2, 1)), 3 + 4);
+    // Stretching an occupied line moves the galaxies after it.
+    assert_eq!(distance(Expansion::uniform(1).with_column(3, 4)), 6 + 4);
+
+    // A solid square of about a hundred thousand galaxies: each axis holds
+    // k galaxies per line, so it adds k^2 * (k^3 - k) / 6.
+    let k = 316u128;
+    let line = "#".repeat(k as usize);
+    let input = vec![line.as_str(); k as usize].join("\n");
+    let universe = Universe::from(input.as_str());
+    assert_eq!(universe.galaxies.len(), 99856);
+    assert_eq!(universe.distance_sum(), 2 * k * k * (k * k * k - k) / 6);
 }
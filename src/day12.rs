@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use lazy_static::lazy_static;
 use regex::Regex;
 
@@ -9,7 +11,7 @@ pub fn solve() {
     let input = std::fs::read_to_string("input/day12.txt").unwrap();
     println!("# Day 12");
     println!("Part 1: {}", part1(&input));
-    //println!("Part 2: {}", part2(&input));
+    println!("Part 2: {}", part2(&input));
 }
 
 struct Row {
@@ -18,6 +20,8 @@ struct Row {
 }
 
 impl Row {
+    /// Brute-force count over every assignment of the unknowns; only the tests
+    /// use it, to check `count_arrangements`.
     fn possible_permutations(&self) -> usize {
         let mut permutations: usize = 0;
         let string = &self.springs;
@@ -62,10 +66,146 @@ impl Row {
         return None;
     }
 
-    fn multiply(&mut self) {
-        self.springs.push('?');
-        self.springs = self.springs.repeat(5);
-        self.groups = self.groups.repeat(5);
+    fn count_arrangements(&self) -> usize {
+        let mut memo = HashMap::new();
+        self.count_from(0, 0, 0, &mut memo)
+    }
+
+    /// Counts arrangements of `springs[pos..]`, given that `group` groups are
+    /// complete and the current run of `#` is `run` long.
+    fn count_from(
+        &self,
+        pos: usize,
+        group: usize,
+        run: usize,
+        memo: &mut HashMap<(usize, usize, usize), usize>,
+    ) -> usize {
+        if pos == self.springs.len() {
+            let done = match run {
+                0 => group == self.groups.len(),
+                _ => group + 1 == self.groups.len() && run == self.groups[group],
+            };
+            return done as usize;
+        }
+        if let Some(&count) = memo.get(&(pos, group, run)) {
+            return count;
+        }
+        let spring = self.springs.as_bytes()[pos];
+        let mut count = 0;
+        if spring != b'.' && group < self.groups.len() && run < self.groups[group] {
+            count += self.count_from(pos + 1, group, run + 1, memo);
+        }
+        if spring != b'#' {
+            if run == 0 {
+                count += self.count_from(pos + 1, group, 0, memo);
+            } else if run == self.groups[group] {
+                count += self.count_from(pos + 1, group + 1, 0, memo);
+            }
+        }
+        memo.insert((pos, group, run), count);
+        count
+    }
+
+    /// Lists up to `limit` arrangements, skipping the first `offset`, in the
+    /// same order as `make_permutations` would find them.
+    fn arrangements(&self, offset: usize, limit: usize) -> Vec<String> {
+        let mut memo = HashMap::new();
+        let mut result = Vec::new();
+        let mut prefix = String::with_capacity(self.springs.len());
+        let mut skip = offset;
+        self.collect_from(
+            0,
+            0,
+            0,
+            &mut prefix,
+            &mut skip,
+            limit,
+            &mut result,
+            &mut memo,
+        );
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn collect_from(
+        &self,
+        pos: usize,
+        group: usize,
+        run: usize,
+        prefix: &mut String,
+        skip: &mut usize,
+        limit: usize,
+        acc: &mut Vec<String>,
+        memo: &mut HashMap<(usize, usize, usize), usize>,
+    ) {
+        if acc.len() >= limit {
+            return;
+        }
+        let count = self.count_from(pos, group, run, memo);
+        if count == 0 {
+            return;
+        }
+        if *skip >= count {
+            *skip -= count;
+            return;
+        }
+        if pos == self.springs.len() {
+            debug_assert!(self.validate_permutation(prefix));
+            acc.push(prefix.clone());
+            return;
+        }
+        let spring = self.springs.as_bytes()[pos];
+        if spring != b'.' && group < self.groups.len() && run < self.groups[group] {
+            prefix.push('#');
+            self.collect_from(pos + 1, group, run + 1, prefix, skip, limit, acc, memo);
+            prefix.pop();
+        }
+        if spring != b'#' {
+            let next = match run {
+                0 => Some(group),
+                _ if run == self.groups[group] => Some(group + 1),
+                _ => None,
+            };
+            if let Some(next) = next {
+                prefix.push('.');
+                self.collect_from(pos + 1, next, 0, prefix, skip, limit, acc, memo);
+                prefix.pop();
+            }
+        }
+    }
+
+    /// For every `?`, counts the arrangements that put a `#` there.
+    fn damaged_counts(&self) -> Vec<(usize, usize)> {
+        let mut memo = HashMap::new();
+        let mut reached: HashMap<(usize, usize), usize> = HashMap::from([((0, 0), 1)]);
+        let mut result = Vec::new();
+        for (pos, spring) in self.springs.bytes().enumerate() {
+            let mut next: HashMap<(usize, usize), usize> = HashMap::new();
+            let mut damaged = 0;
+            for (&(group, run), &ways) in reached.iter() {
+                if spring != b'.' && group < self.groups.len() && run < self.groups[group] {
+                    damaged += ways * self.count_from(pos + 1, group, run + 1, &mut memo);
+                    *next.entry((group, run + 1)).or_default() += ways;
+                }
+                if spring != b'#' {
+                    if run == 0 {
+                        *next.entry((group, 0)).or_default() += ways;
+                    } else if run == self.groups[group] {
+                        *next.entry((group + 1, 0)).or_default() += ways;
+                    }
+                }
+            }
+            if spring == b'?' {
+                result.push((pos, damaged));
+            }
+            reached = next;
+        }
+        result
+    }
+
+    fn unfold(&mut self, factor: usize) {
+        self.springs = vec![self.springs.as_str(); factor].join("?");
+        self.groups = self.groups.repeat(factor);
     }
 }
 
@@ -86,13 +226,13 @@ impl From<&str> for Row {
 
 fn part1(input: &str) -> usize {
     let rows: Vec<Row> = input.trim().lines().map(Row::from).collect();
-    rows.iter().map(Row::possible_permutations).sum()
+    rows.iter().map(Row::count_arrangements).sum()
 }
 
 fn part2(input: &str) -> usize {
     let mut rows: Vec<Row> = input.trim().lines().map(Row::from).collect();
-    rows.iter_mut().for_each(Row::multiply);
-    rows.iter().map(Row::possible_permutations).sum()
+    rows.iter_mut().for_each(|row| row.unfold(5));
+    rows.iter().map(Row::count_arrangements).sum()
 }
 
 #[test]
@@ -107,5 +247,31 @@ fn test() {
 ";
 
     assert_eq!(part1(input), 21);
-    //assert_eq!(part2(input), 525152);
+    assert_eq!(part2(input), 525152);
+
+    let unfolded = [1, 16384, 1, 16, 2500, 506250];
+    for (line, expected) in input.trim().lines().zip(unfolded) {
+        let mut row = Row::from(line);
+        assert_eq!(row.count_arrangements(), row.possible_permutations());
+        row.unfold(5);
+        assert_eq!(row.count_arrangements(), expected);
+    }
+
+    let row = Row::from("?###???????? 3,2,1");
+    let all = row.arrangements(0, usize::MAX);
+    assert_eq!(all.len(), 10);
+    assert_eq!(all[0], ".###.##.#...");
+    assert_eq!(all[9], ".###....##.#");
+    assert!(all.iter().all(|a| row.validate_permutation(a)));
+    assert_eq!(row.arrangements(3, 4), all[3..7].to_vec());
+    assert_eq!(row.arrangements(8, 5), all[8..].to_vec());
+    assert!(row.arrangements(10, 5).is_empty());
+
+    let counts = row.damaged_counts();
+    assert_eq!(counts.len(), 9);
+    for (pos, count) in counts {
+        let expected = all.iter().filter(|a| a.as_bytes()[pos] == b'#').count();
+        assert_eq!(count, expected);
+    }
+    assert_eq!(row.damaged_counts()[0], (0, 0));
 }
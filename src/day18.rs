@@ -1,8 +1,16 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::polygon::{self, measure, Point};
+
 pub fn solve() {
     let input = std::fs::read_to_string("input/day18.txt").unwrap();
     println!("# Day 18");
     println!("Part 1: {}", part1(&input));
     println!("Part 2: {}", part2(&input));
+    if std::env::args().any(|arg| arg == "--svg") {
+        let rules = input.trim().lines().map(Rule::from).collect::<Vec<_>>();
+        print!("{}", render_svg(&rules, Rule::step, 800.0).unwrap());
+    }
 }
 
 struct Rule {
@@ -38,6 +46,14 @@ impl Rule {
         let hex = self.color.chars().take(5).collect::<String>();
         isize::from_str_radix(&hex, 16).unwrap()
     }
+
+    fn step(&self) -> ((isize, isize), isize) {
+        (self.offset(), self.count)
+    }
+
+    fn step_2(&self) -> ((isize, isize), isize) {
+        (self.offset_2(), self.bigcount())
+    }
 }
 
 impl From<&str> for Rule {
@@ -56,36 +72,93 @@ impl From<&str> for Rule {
     }
 }
 
-fn shoelace(points: &[(isize, isize)]) -> isize {
-    let mut sum = 0;
-    for i in 0..points.len() - 1 {
-        let (x1, y1) = points[i];
-        let (x2, y2) = points[i + 1];
+#[derive(Debug, PartialEq)]
+enum PlanError {
+    NotClosed { end: Point },
+    Overflow { rule: usize },
+    SelfIntersection { first: usize, second: usize },
+}
 
-        sum += x1 * y2 - x2 * y1;
+impl Display for PlanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlanError::NotClosed { end } => {
+                write!(f, "trench ends at {:?} instead of the start", end)
+            }
+            PlanError::Overflow { rule } => write!(f, "rule {} digs off the map", rule),
+            PlanError::SelfIntersection { first, second } => {
+                write!(f, "rules {} and {} dig into each other", first, second)
+            }
+        }
     }
-    let (x1, y1) = points.first().unwrap();
-    let (x2, y2) = points.last().unwrap();
-
-    sum += x1 * y2 - x2 * y1;
+}
 
-    sum / 2
+/// The corners of the trench, starting at the origin, so that rule `i` digs
+/// from corner `i` to the next one. Fails unless the trench is a simple loop.
+fn corners(
+    rules: &[Rule],
+    step: fn(&Rule) -> ((isize, isize), isize),
+) -> Result<Vec<Point>, PlanError> {
+    let mut points = vec![(0, 0)];
+    let (mut x, mut y): Point = (0, 0);
+    for (index, rule) in rules.iter().enumerate() {
+        let ((dx, dy), count) = step(rule);
+        let moved = |at: isize, d: isize| count.checked_mul(d).and_then(|d| at.checked_add(d));
+        match (moved(x, dx), moved(y, dy)) {
+            (Some(nx), Some(ny)) => (x, y) = (nx, ny),
+            _ => return Err(PlanError::Overflow { rule: index }),
+        }
+        points.push((x, y));
+    }
+    let end = points.pop().unwrap();
+    if end != (0, 0) {
+        return Err(PlanError::NotClosed { end });
+    }
+    if let Some(&(first, second)) = polygon::self_intersections(&points).first() {
+        return Err(PlanError::SelfIntersection { first, second });
+    }
+    Ok(points)
 }
 
-fn interior_points(points: &[(isize, isize)]) -> isize {
-    let area = shoelace(&points);
-    let mut perimeter = 0;
-    for i in 0..points.len() - 1 {
-        let (x1, y1) = points[i];
-        let (x2, y2) = points[i + 1];
+/// Draws the trench as an SVG `size` pixels across its longer side, with
+/// each edge stroked in its rule's colour.
+fn render_svg(
+    rules: &[Rule],
+    step: fn(&Rule) -> ((isize, isize), isize),
+    size: f64,
+) -> Result<String, PlanError> {
+    let mut points = corners(rules, step)?;
+    points.push((0, 0));
+    // Spans are taken in f64, as they can exceed isize on valid plans.
+    let span = |coord: fn(&Point) -> isize| {
+        let min = points.iter().map(coord).min().unwrap();
+        let max = points.iter().map(coord).max().unwrap();
+        (min, max as f64 - min as f64)
+    };
+    let ((min_x, width), (min_y, height)) = (span(|p| p.0), span(|p| p.1));
+    let scale = size / width.max(height).max(1.0);
+    let margin = 2.0;
+    let project = |(x, y): Point| {
+        (
+            (x as f64 - min_x as f64) * scale + margin,
+            (y as f64 - min_y as f64) * scale + margin,
+        )
+    };
 
-        perimeter += (x2 - x1).abs() + (y2 - y1).abs();
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w:.1}\" height=\"{h:.1}\" viewBox=\"0 0 {w:.1} {h:.1}\">\n",
+        w = width * scale + 2.0 * margin,
+        h = height * scale + 2.0 * margin,
+    );
+    for (rule, edge) in rules.iter().zip(points.windows(2)) {
+        let ((x1, y1), (x2, y2)) = (project(edge[0]), project(edge[1]));
+        svg.push_str(&format!(
+            "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"#{}\" stroke-width=\"2\" stroke-linecap=\"square\"/>\n",
+            x1, y1, x2, y2, rule.color
+        ));
     }
-    let (x1, y1) = points.first().unwrap();
-    let (x2, y2) = points.last().unwrap();
-    perimeter += (x2 - x1).abs() + (y2 - y1).abs();
-
-    ((perimeter as isize / 2) - 1 - area).abs() + perimeter as isize
+    svg.push_str("</svg>\n");
+    Ok(svg)
 }
 
 fn print_points(points: &[(isize, isize)]) {
@@ -104,33 +177,54 @@ fn print_points(points: &[(isize, isize)]) {
     }
 }
 
-fn part1(input: &str) -> usize {
-    let rules = input.trim().lines().map(Rule::from).collect::<Vec<_>>();
-    let mut points: Vec<(isize, isize)> = vec![];
+/// Cubic metres dug out: the trench itself plus everything it encloses.
+fn lagoon(rules: &[Rule], step: fn(&Rule) -> ((isize, isize), isize)) -> usize {
+    let points = corners(rules, step).unwrap_or_else(|e| panic!("{}", e));
+    let measured = measure(&points).expect("lagoon too large to measure");
+    usize::try_from(measured.enclosed()).expect("lagoon does not fit in usize")
+}
+
+/// Digs the plan out cell by cell and floods the outside, for checking
+/// `lagoon` on small plans.
+fn flood_fill(rules: &[Rule], step: fn(&Rule) -> ((isize, isize), isize)) -> usize {
+    let mut trench = HashSet::from([(0, 0)]);
     let (mut x, mut y) = (0, 0);
     for rule in rules {
-        let (dx, dy) = rule.offset();
-        x += dx * rule.count;
-        y += dy * rule.count;
-        points.push((x, y));
+        let ((dx, dy), count) = step(rule);
+        for _ in 0..count {
+            x += dx;
+            y += dy;
+            trench.insert((x, y));
+        }
     }
+    let min_x = trench.iter().map(|p| p.0).min().unwrap() - 1;
+    let max_x = trench.iter().map(|p| p.0).max().unwrap() + 1;
+    let min_y = trench.iter().map(|p| p.1).min().unwrap() - 1;
+    let max_y = trench.iter().map(|p| p.1).max().unwrap() + 1;
+    let mut outside = HashSet::from([(min_x, min_y)]);
+    let mut queue = vec![(min_x, min_y)];
+    while let Some((x, y)) = queue.pop() {
+        for (nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+            if (min_x..=max_x).contains(&nx)
+                && (min_y..=max_y).contains(&ny)
+                && !trench.contains(&(nx, ny))
+                && outside.insert((nx, ny))
+            {
+                queue.push((nx, ny));
+            }
+        }
+    }
+    ((max_x - min_x + 1) * (max_y - min_y + 1)) as usize - outside.len()
+}
 
-    interior_points(&points) as usize
+fn part1(input: &str) -> usize {
+    let rules = input.trim().lines().map(Rule::from).collect::<Vec<_>>();
+    lagoon(&rules, Rule::step)
 }
 
 fn part2(input: &str) -> usize {
     let rules = input.trim().lines().map(Rule::from).collect::<Vec<_>>();
-    let mut points: Vec<(isize, isize)> = vec![];
-    let (mut x, mut y) = (0, 0);
-    for rule in rules {
-        let (dx, dy) = rule.offset_2();
-        let count = rule.bigcount();
-        x += dx * count;
-        y += dy * count;
-        points.push((x, y));
-    }
-
-    interior_points(&points) as usize
+    lagoon(&rules, Rule::step_2)
 }
 
 #[test]
@@ -153,4 +247,125 @@ U 2 (#7a21e3)
 ";
     assert_eq!(part1(input), 62);
     assert_eq!(part2(input), 952408144115);
+
+    let rules = input.trim().lines().map(Rule::from).collect::<Vec<_>>();
+    assert_eq!(corners(&rules, Rule::step).unwrap().len(), 14);
+    let svg = render_svg(&rules, Rule::step, 100.0).unwrap();
+    assert!(svg
+        .starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"70.7\" height=\"104.0\""));
+    assert_eq!(svg.matches("<line ").count(), 14);
+    assert!(svg.contains("<line x1=\"2.0\" y1=\"2.0\" x2=\"68.7\" y2=\"2.0\" stroke=\"#70c710\""));
+    assert!(render_svg(&rules, Rule::step_2, 500.0)
+        .unwrap()
+        .contains("stroke=\"#7a21e3\""));
+
+    let parse = |plan: &str| plan.trim().lines().map(Rule::from).collect::<Vec<_>>();
+    let open = parse("R 2 (#000000)\nD 2 (#000000)");
+    assert_eq!(
+        corners(&open, Rule::step),
+        Err(PlanError::NotClosed { end: (2, 2) })
+    );
+    let crossing = parse(
+        "
+R 2 (#000000)
+D 2 (#000000)
+L 1 (#000000)
+U 3 (#000000)
+L 1 (#000000)
+D 1 (#000000)
+",
+    );
+    let error = corners(&crossing, Rule::step).unwrap_err();
+    assert_eq!(
+        error,
+        PlanError::SelfIntersection {
+            first: 0,
+            second: 3
+        }
+    );
+    assert_eq!(error.to_string(), "rules 0 and 3 dig into each other");
+    let points = corners(&rules, Rule::step).unwrap();
+    assert_eq!(
+        measure(&points).unwrap().orientation,
+        polygon::Orientation::Clockwise
+    );
+    let far = parse("R 9223372036854775807 (#000000)\nR 1 (#000000)");
+    assert_eq!(
+        corners(&far, Rule::step),
+        Err(PlanError::Overflow { rule: 1 })
+    );
+    assert_eq!(
+        render_svg(&far, Rule::step, 100.0),
+        Err(PlanError::Overflow { rule: 1 })
+    );
+    let back = parse("R 2 (#000000)\nL 2 (#000000)");
+    assert_eq!(
+        corners(&back, Rule::step),
+        Err(PlanError::SelfIntersection {
+            first: 0,
+            second: 1
+        })
+    );
+}
+
+/// Compares `lagoon` with `flood_fill` on random small plans, closed off with
+/// one horizontal and one vertical rule and kept if they form a simple loop.
+#[test]
+fn random_plans() {
+    let mut seed = 0x2545f4914f6cdd1du64;
+    let mut next = |bound: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % bound) as isize
+    };
+    let mut checked = 0;
+    for _ in 0..2000 {
+        let mut plan = Vec::new();
+        let (mut x, mut y) = (0, 0);
+        for _ in 0..2 + next(5) {
+            let dir = ['U', 'D', 'L', 'R'][next(4) as usize];
+            let count = 1 + next(4);
+            plan.push(format!("{} {} (#123456)", dir, count));
+            match dir {
+                'U' => y -= count,
+                'D' => y += count,
+                'L' => x -= count,
+                _ => x += count,
+            }
+        }
+        match x {
+            x if x > 0 => plan.push(format!("L {} (#123456)", x)),
+            x if x < 0 => plan.push(format!("R {} (#123456)", -x)),
+            _ => (),
+        }
+        match y {
+            y if y > 0 => plan.push(format!("U {} (#123456)", y)),
+            y if y < 0 => plan.push(format!("D {} (#123456)", -y)),
+            _ => (),
+        }
+        let rules = plan
+            .iter()
+            .map(|line| Rule::from(line.as_str()))
+            .collect::<Vec<_>>();
+        let points = match corners(&rules, Rule::step) {
+            Ok(points) => points,
+            Err(_) => continue,
+        };
+        assert_eq!(
+            lagoon(&rules, Rule::step),
+            flood_fill(&rules, Rule::step),
+            "{:?}",
+            plan
+        );
+
+        let mut reversed = points.clone();
+        reversed.reverse();
+        let (forward, backward) = (measure(&points).unwrap(), measure(&reversed).unwrap());
+        assert_eq!(forward.enclosed(), backward.enclosed());
+        assert_ne!(forward.orientation, backward.orientation);
+        assert_ne!(forward.orientation, polygon::Orientation::Degenerate);
+        checked += 1;
+    }
+    assert!(checked > 100, "only {} plans were simple loops", checked);
 }
@@ -1,20 +1,87 @@
-use std::{collections::HashMap, ops::RangeInclusive, panic};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::{self, Write},
+    ops::RangeInclusive,
+    panic,
+};
+
+use crate::interval::{IntervalBox, IntervalSet};
 
 pub fn solve() {
     let input = std::fs::read_to_string("input/day19.txt").unwrap();
     println!("# Day 19");
     println!("Part 1: {}", part1(&input));
     println!("Part 2: {}", part2(&input));
+    if std::env::args().any(|arg| arg == "--analyse") {
+        let system = parse(&input);
+        for finding in analyse(&system) {
+            println!("{}", finding);
+        }
+    }
+    if std::env::args().any(|arg| arg == "--optimise") {
+        let mut system = parse(&input);
+        system.parts.clear();
+        system.optimise();
+        print!("{}", system);
+    }
+    if std::env::args().any(|arg| arg == "--boxes") {
+        let system = parse(&input);
+        write_csv(
+            &accepted_boxes(&system),
+            &system.categories,
+            &mut io::stdout(),
+        )
+        .unwrap();
+    }
+    if std::env::args().any(|arg| arg == "--explain") {
+        let system = parse(&input);
+        let program = Program::compile(&system.workflows, &system.categories).unwrap();
+        for part in &system.parts {
+            println!("{}: {}", part, program.explain(part));
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Operator {
     GreaterThan,
     LessThan,
-    None,
+    AtLeast,
+    AtMost,
+    Equal,
 }
 
-#[derive(Debug, Clone)]
+impl Operator {
+    /// Two-character operators come first so `>=` is not read as `>`.
+    const SYMBOLS: [(&'static str, Operator); 5] = [
+        (">=", Operator::AtLeast),
+        ("<=", Operator::AtMost),
+        ("==", Operator::Equal),
+        (">", Operator::GreaterThan),
+        ("<", Operator::LessThan),
+    ];
+
+    fn symbol(&self) -> &'static str {
+        Self::SYMBOLS
+            .iter()
+            .find(|(_, operator)| operator == self)
+            .unwrap()
+            .0
+    }
+
+    fn test(&self, rating: usize, value: usize) -> bool {
+        match self {
+            Operator::GreaterThan => rating > value,
+            Operator::LessThan => rating < value,
+            Operator::AtLeast => rating >= value,
+            Operator::AtMost => rating <= value,
+            Operator::Equal => rating == value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum Operation {
     Accepted,
     Rejected,
@@ -31,7 +98,19 @@ impl From<&str> for Operation {
     }
 }
 
-#[derive(Debug)]
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Accepted => write!(f, "A"),
+            Operation::Rejected => write!(f, "R"),
+            Operation::ProcessWith(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Parts that match none of a workflow's rules are rejected, as if it ended
+/// with an `R` fallback.
+#[derive(Debug, Clone)]
 struct Workflow {
     name: String,
     rules: Vec<Rule>,
@@ -40,31 +119,29 @@ struct Workflow {
 impl Workflow {
     fn process_part(&self, part: &Part) -> Operation {
         for rule in self.rules[..].iter() {
-            if rule.operator == Operator::None {
-                return rule.operation.clone();
-            }
-            let value = match rule.key {
-                'x' => part.x,
-                'm' => part.m,
-                'a' => part.a,
-                's' => part.s,
-                _ => 0,
-            };
-            match rule.operator {
-                Operator::GreaterThan => {
-                    if value > rule.value {
-                        return rule.operation.clone();
-                    }
-                }
-                Operator::LessThan => {
-                    if value < rule.value {
+            match &rule.condition {
+                None => return rule.operation.clone(),
+                Some(condition) => {
+                    if condition
+                        .operator
+                        .test(part.get(&condition.key), condition.value)
+                    {
                         return rule.operation.clone();
                     }
                 }
-                _ => {}
             }
         }
-        panic!("No rule found for part: {:?}", part);
+        Operation::Rejected
+    }
+
+    fn has_fallback(&self) -> bool {
+        matches!(
+            self.rules.last(),
+            Some(Rule {
+                condition: None,
+                ..
+            })
+        )
     }
 }
 
@@ -83,149 +160,511 @@ impl From<&str> for Workflow {
     }
 }
 
-#[derive(Debug)]
-struct Rule {
+impl Display for Workflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rules = self.rules.iter().map(Rule::to_string).collect::<Vec<_>>();
+        write!(f, "{}{{{}}}", self.name, rules.join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    key: String,
     operator: Operator,
     value: usize,
-    key: char,
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.key, self.operator.symbol(), self.value)
+    }
+}
+
+/// A rule without a condition is the fallback at the end of a workflow.
+#[derive(Debug, Clone)]
+struct Rule {
+    condition: Option<Condition>,
     operation: Operation,
 }
 
 impl From<&str> for Rule {
     fn from(value: &str) -> Self {
-        let mut chars = value.chars();
-        if !chars.clone().any(|c| c == '>' || c == '<') {
-            return Rule {
-                operator: Operator::None,
-                value: 0,
-                key: '\0',
-                operation: Operation::from(value),
-            };
-        }
-        let key = chars.next().unwrap();
-        let operator = match chars.next().unwrap() {
-            '>' => Operator::GreaterThan,
-            '<' => Operator::LessThan,
-            _ => Operator::None,
+        let (condition, target) = match value.split_once(':') {
+            Some(split) => split,
+            None => {
+                return Rule {
+                    condition: None,
+                    operation: Operation::from(value),
+                }
+            }
         };
-        let mut parts = value.split(['>', '<', ':']).skip(1);
-        let value = parts.next().unwrap().parse::<usize>().unwrap();
-        let operation = Operation::from(parts.next().unwrap());
+        let at = condition
+            .find(['<', '>', '='])
+            .unwrap_or_else(|| panic!("no operator in {}", condition));
+        let (key, rest) = condition.split_at(at);
+        let (symbol, operator) = Operator::SYMBOLS
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .unwrap_or_else(|| panic!("unknown operator in {}", condition));
+        let value = rest[symbol.len()..].parse::<usize>().unwrap();
         Rule {
-            operator,
-            value,
-            key,
-            operation,
+            condition: Some(Condition {
+                key: key.to_string(),
+                operator: *operator,
+                value,
+            }),
+            operation: Operation::from(target),
+        }
+    }
+}
+
+impl Rule {
+    fn step(&self, workflow: &str, index: usize) -> Step {
+        Step {
+            workflow: workflow.to_string(),
+            rule: Some(index),
+            condition: match &self.condition {
+                Some(condition) => condition.to_string(),
+                None => "else".to_string(),
+            },
+            target: self.operation.to_string(),
+        }
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.condition {
+            Some(condition) => write!(f, "{}:{}", condition, self.operation),
+            None => write!(f, "{}", self.operation),
         }
     }
 }
 
+/// Rating category names in the order they were first seen in the input.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Categories {
+    names: Vec<String>,
+}
+
+impl Categories {
+    fn insert(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        }
+    }
+
+    fn index(&self, name: &str) -> usize {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .unwrap_or_else(|| panic!("unknown category {}", name))
+    }
+
+    fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
 #[derive(Debug)]
 struct Part {
-    x: usize,
-    m: usize,
-    a: usize,
-    s: usize,
+    ratings: Vec<(String, usize)>,
 }
 
 impl Part {
     fn value(&self) -> usize {
-        self.x + self.m + self.a + self.s
+        self.ratings.iter().map(|(_, rating)| rating).sum()
+    }
+
+    /// A category the part does not list is rated 0.
+    fn get(&self, key: &str) -> usize {
+        self.ratings
+            .iter()
+            .find(|(name, _)| name == key)
+            .map_or(0, |(_, rating)| *rating)
+    }
+
+    fn values(&self, categories: &Categories) -> Vec<usize> {
+        categories.names.iter().map(|name| self.get(name)).collect()
     }
 }
 
 impl From<&str> for Part {
     fn from(value: &str) -> Self {
-        let mut parts = value
+        let ratings = value
             .trim_start_matches('{')
             .trim_end_matches('}')
             .split(',')
-            .map(|s| s[2..].to_string().parse::<usize>().unwrap());
-        let x = parts.next().unwrap();
-        let m = parts.next().unwrap();
-        let a = parts.next().unwrap();
-        let s = parts.next().unwrap();
-        Part { x, m, a, s }
+            .map(|s| {
+                let (name, rating) = s.split_once('=').unwrap();
+                (name.to_string(), rating.parse::<usize>().unwrap())
+            })
+            .collect();
+        Part { ratings }
     }
 }
 
-#[derive(Debug, Clone)]
-struct PartRange {
-    x: RangeInclusive<usize>,
-    m: RangeInclusive<usize>,
-    a: RangeInclusive<usize>,
-    s: RangeInclusive<usize>,
+impl Display for Part {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ratings = self
+            .ratings
+            .iter()
+            .map(|(name, rating)| format!("{}={}", name, rating))
+            .collect::<Vec<_>>();
+        write!(f, "{{{}}}", ratings.join(","))
+    }
 }
 
-impl Default for PartRange {
-    fn default() -> Self {
+/// Workflows, parts and the categories they mention. Every category shares
+/// the same rating bounds, `1..=4000` unless set with `with_bounds`.
+#[derive(Debug)]
+struct System {
+    categories: Categories,
+    workflows: Vec<Workflow>,
+    parts: Vec<Part>,
+    bounds: RangeInclusive<usize>,
+}
+
+impl From<&str> for System {
+    fn from(input: &str) -> Self {
+        let mut strparts = input.trim().split("\n\n");
+        let workflows = strparts
+            .next()
+            .unwrap()
+            .lines()
+            .map(Workflow::from)
+            .collect::<Vec<_>>();
+        let parts = strparts
+            .next()
+            .unwrap_or("")
+            .lines()
+            .map(Part::from)
+            .collect::<Vec<_>>();
+        let mut categories = Categories::default();
+        for part in &parts {
+            for (name, _) in &part.ratings {
+                categories.insert(name);
+            }
+        }
+        for workflow in &workflows {
+            for condition in workflow.rules.iter().filter_map(|r| r.condition.as_ref()) {
+                categories.insert(&condition.key);
+            }
+        }
+        System {
+            categories,
+            workflows,
+            parts,
+            bounds: 1..=4000,
+        }
+    }
+}
+
+impl System {
+    fn with_bounds(mut self, bounds: RangeInclusive<usize>) -> Self {
+        self.bounds = bounds;
+        self
+    }
+
+    fn full_range(&self) -> PartRange {
         PartRange {
-            x: 1..=4000,
-            m: 1..=4000,
-            a: 1..=4000,
-            s: 1..=4000,
+            bounds: IntervalBox::uniform(self.bounds.clone(), self.categories.len())
+                .expect("empty rating bounds"),
+        }
+    }
+
+    fn map(&self) -> HashMap<&str, &Workflow> {
+        self.workflows
+            .iter()
+            .map(|w| (w.name.as_str(), w))
+            .collect()
+    }
+}
+
+/// Writes the system back out in the puzzle syntax.
+impl Display for System {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for workflow in &self.workflows {
+            writeln!(f, "{}", workflow)?;
         }
+        if !self.parts.is_empty() {
+            writeln!(f)?;
+            for part in &self.parts {
+                writeln!(f, "{}", part)?;
+            }
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone)]
+struct PartRange {
+    bounds: IntervalBox<usize>,
+}
+
 impl PartRange {
-    fn get_mut(&mut self, key: char) -> &mut RangeInclusive<usize> {
-        match key {
-            'x' => &mut self.x,
-            'm' => &mut self.m,
-            'a' => &mut self.a,
-            's' => &mut self.s,
-            _ => panic!("EVERYONE PANIC!"),
-        }
-    }
-    fn get(&self, key: char) -> &RangeInclusive<usize> {
-        match key {
-            'x' => &self.x,
-            'm' => &self.m,
-            'a' => &self.a,
-            's' => &self.s,
-            _ => panic!("EVERYONE PANIC!"),
-        }
-    }
-    fn with_rule(&self, rule: &Rule) -> Option<(Self, Self)> {
-        let mut new = self.clone();
-        let mut inverse = self.clone();
-        match rule.operator {
+    fn get(&self, field: usize) -> &RangeInclusive<usize> {
+        self.bounds.get(field)
+    }
+    fn volume(&self) -> usize {
+        self.bounds.volume()
+    }
+    /// Splits the range into the part the rule matches and whatever is left
+    /// for the following rules. `==` can leave pieces on both sides.
+    fn with_rule(&self, rule: &Rule, categories: &Categories) -> (Option<Self>, Vec<Self>) {
+        let condition = match &rule.condition {
+            Some(condition) => condition,
+            None => return (Some(self.clone()), Vec::new()),
+        };
+        let field = categories.index(&condition.key);
+        let value = condition.value;
+        let (matched, rest) = match condition.operator {
             Operator::GreaterThan => {
-                let new_key = new.get_mut(rule.key);
-                let inverse_key = inverse.get_mut(rule.key);
-                *new_key = rule.value + 1..=*self.get(rule.key).end();
-                *inverse_key = *self.get(rule.key).start()..=rule.value;
+                let (below, above) = self.bounds.split_at(field, value + 1);
+                (above, vec![below])
+            }
+            Operator::AtLeast => {
+                let (below, above) = self.bounds.split_at(field, value);
+                (above, vec![below])
             }
             Operator::LessThan => {
-                let new_key = new.get_mut(rule.key);
-                let inverse_key = inverse.get_mut(rule.key);
-                *new_key = *self.get(rule.key).start()..=rule.value - 1;
-                *inverse_key = rule.value..=*self.get(rule.key).end();
+                let (below, above) = self.bounds.split_at(field, value);
+                (below, vec![above])
+            }
+            Operator::AtMost => {
+                let (below, above) = self.bounds.split_at(field, value + 1);
+                (below, vec![above])
+            }
+            Operator::Equal => {
+                let (below, rest) = self.bounds.split_at(field, value);
+                let (equal, above) = match rest {
+                    Some(rest) => rest.split_at(field, value + 1),
+                    None => (None, None),
+                };
+                (equal, vec![below, above])
+            }
+        };
+        let wrap = |bounds: IntervalBox<usize>| PartRange { bounds };
+        (
+            matched.map(wrap),
+            rest.into_iter().flatten().map(wrap).collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Accept,
+    Reject,
+    Jump(usize),
+}
+
+/// `operator` is `None` for the fallback rule, which always matches.
+/// `implicit` marks the reject that `compile` adds to a workflow without one.
+#[derive(Debug, Clone, Copy)]
+struct Instruction {
+    field: usize,
+    operator: Option<Operator>,
+    value: usize,
+    target: Target,
+    implicit: bool,
+}
+
+/// Workflows flattened into one instruction list. Jumps point at the first
+/// instruction of the target workflow, so evaluating a part needs no lookups.
+/// A workflow without a fallback gets a rejecting one, so a part can never
+/// run on into the next workflow.
+#[derive(Debug)]
+struct Program {
+    names: Vec<String>,
+    categories: Categories,
+    starts: Vec<usize>,
+    instructions: Vec<Instruction>,
+    entry: usize,
+}
+
+impl Program {
+    fn compile(workflows: &[Workflow], categories: &Categories) -> Result<Self, String> {
+        let names = workflows.iter().map(|w| w.name.clone()).collect::<Vec<_>>();
+        let ids: HashMap<&str, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| (name.as_str(), id))
+            .collect();
+        let mut starts = Vec::with_capacity(workflows.len());
+        let mut offset = 0;
+        for workflow in workflows {
+            starts.push(offset);
+            offset += workflow.rules.len() + !workflow.has_fallback() as usize;
+        }
+        let mut instructions = Vec::with_capacity(offset);
+        for workflow in workflows {
+            for rule in &workflow.rules {
+                let target = match &rule.operation {
+                    Operation::Accepted => Target::Accept,
+                    Operation::Rejected => Target::Reject,
+                    Operation::ProcessWith(name) => match ids.get(name.as_str()) {
+                        Some(&id) => Target::Jump(starts[id]),
+                        None => return Err(format!("unknown workflow {}", name)),
+                    },
+                };
+                let instruction = match &rule.condition {
+                    Some(condition) => Instruction {
+                        field: categories.index(&condition.key),
+                        operator: Some(condition.operator),
+                        value: condition.value,
+                        target,
+                        implicit: false,
+                    },
+                    None => Instruction {
+                        field: 0,
+                        operator: None,
+                        value: 0,
+                        target,
+                        implicit: false,
+                    },
+                };
+                instructions.push(instruction);
+            }
+            if !workflow.has_fallback() {
+                instructions.push(Instruction {
+                    field: 0,
+                    operator: None,
+                    value: 0,
+                    target: Target::Reject,
+                    implicit: true,
+                });
+            }
+        }
+        let entry = match ids.get("in") {
+            Some(&id) => starts[id],
+            None => return Err("no workflow named in".to_string()),
+        };
+        Ok(Program {
+            names,
+            categories: categories.clone(),
+            starts,
+            instructions,
+            entry,
+        })
+    }
+
+    /// The part's ratings in category order, as `accepts` expects them.
+    fn ratings(&self, part: &Part) -> Vec<usize> {
+        part.values(&self.categories)
+    }
+
+    fn accepts(&self, ratings: &[usize]) -> bool {
+        self.run(ratings, |_| {})
+    }
+
+    /// Lists the rule that fired in every workflow the part passed through.
+    fn explain(&self, part: &Part) -> Explanation {
+        let mut steps = Vec::new();
+        let accepted = self.run(&self.ratings(part), |pc| steps.push(self.describe(pc)));
+        Explanation { steps, accepted }
+    }
+
+    fn workflow_at(&self, pc: usize) -> usize {
+        self.starts.partition_point(|&start| start <= pc) - 1
+    }
+
+    fn describe(&self, pc: usize) -> Step {
+        let instruction = &self.instructions[pc];
+        let workflow = self.workflow_at(pc);
+        let condition = match instruction.operator {
+            Some(operator) => format!(
+                "{}{}{}",
+                self.categories.names[instruction.field],
+                operator.symbol(),
+                instruction.value
+            ),
+            None if instruction.implicit => "no rule matched".to_string(),
+            None => "else".to_string(),
+        };
+        let target = match instruction.target {
+            Target::Accept => "A".to_string(),
+            Target::Reject => "R".to_string(),
+            Target::Jump(start) => self.names[self.workflow_at(start)].clone(),
+        };
+        Step {
+            workflow: self.names[workflow].clone(),
+            rule: match instruction.implicit {
+                true => None,
+                false => Some(pc - self.starts[workflow]),
+            },
+            condition,
+            target,
+        }
+    }
+
+    /// Evaluates a part, calling `fired` with each instruction that matched.
+    fn run(&self, ratings: &[usize], mut fired: impl FnMut(usize)) -> bool {
+        let mut pc = self.entry;
+        loop {
+            let instruction = &self.instructions[pc];
+            let matched = match instruction.operator {
+                Some(operator) => operator.test(ratings[instruction.field], instruction.value),
+                None => true,
+            };
+            if !matched {
+                pc += 1;
+                continue;
+            }
+            fired(pc);
+            match instruction.target {
+                Target::Accept => return true,
+                Target::Reject => return false,
+                Target::Jump(start) => pc = start,
             }
-            Operator::None => return None,
         }
-        Some((new, inverse))
     }
 }
 
-fn part1(input: &str) -> usize {
+/// `rule` is `None` for the reject a compiled workflow falls through to.
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    workflow: String,
+    rule: Option<usize>,
+    condition: String,
+    target: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct Explanation {
+    steps: Vec<Step>,
+    accepted: bool,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.workflow, self.condition, self.target
+        )
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let steps = self.steps.iter().map(Step::to_string).collect::<Vec<_>>();
+        write!(f, "{}", steps.join("; "))
+    }
+}
+
+fn parse(input: &str) -> System {
+    System::from(input)
+}
+
+fn interpret(workflows: &[Workflow], parts: &[Part]) -> usize {
     let mut map: HashMap<&str, &Workflow> = HashMap::new();
-    let mut strparts = input.trim().split("\n\n");
-    let workflows = strparts
-        .next()
-        .unwrap()
-        .lines()
-        .map(Workflow::from)
-        .collect::<Vec<_>>();
-    let parts = strparts
-        .next()
-        .unwrap()
-        .lines()
-        .map(Part::from)
-        .collect::<Vec<_>>();
     for workflow in workflows.iter() {
-        map.insert(&workflow.name, &workflow);
+        map.insert(&workflow.name, workflow);
     }
     let initial = map.get("in").unwrap();
     let mut sum = 0;
@@ -251,70 +690,636 @@ fn part1(input: &str) -> usize {
     sum
 }
 
+fn accepted_sum(system: &System) -> usize {
+    let program = Program::compile(&system.workflows, &system.categories).unwrap();
+    system
+        .parts
+        .iter()
+        .filter(|part| program.accepts(&program.ratings(part)))
+        .map(Part::value)
+        .sum()
+}
+
+fn part1(input: &str) -> usize {
+    accepted_sum(&parse(input))
+}
+
 fn do_part2(
     map: &HashMap<&str, &Workflow>,
+    categories: &Categories,
     key: &str,
     current: PartRange,
-    acc: &mut Vec<PartRange>,
+    path: &mut Vec<Step>,
+    acc: &mut Vec<AcceptedBox>,
 ) {
-    let mut kaastaart = current;
+    let mut remaining = vec![current];
     if let Some(workflow) = map.get(key) {
-        for rule in &workflow.rules {
-            match kaastaart.with_rule(&rule) {
-                Some((new, inverse)) => {
+        for (index, rule) in workflow.rules.iter().enumerate() {
+            let mut rest = Vec::new();
+            for range in remaining {
+                let (new, inverse) = range.with_rule(rule, categories);
+                if let Some(new) = new {
+                    path.push(rule.step(&workflow.name, index));
                     match &rule.operation {
-                        Operation::Accepted => {
-                            acc.push(new);
-                        }
-                        Operation::Rejected => (),
+                        Operation::Accepted => acc.push(AcceptedBox {
+                            range: new,
+                            chains: vec![path.clone()],
+                        }),
                         Operation::ProcessWith(name) => {
-                            do_part2(map, name, new, acc);
+                            do_part2(map, categories, name, new, path, acc);
                         }
+                        Operation::Rejected => (),
                     }
-                    kaastaart = inverse;
+                    path.pop();
                 }
-
-                None => match &rule.operation {
-                    Operation::Accepted => {
-                        acc.push(kaastaart.clone());
-                    }
-                    Operation::Rejected => (),
-                    Operation::ProcessWith(name) => {
-                        do_part2(map, name, kaastaart.clone(), acc);
-                    }
-                },
+                rest.extend(inverse);
+            }
+            if rest.is_empty() {
+                return;
             }
+            remaining = rest;
         }
+        // Whatever is left matched no rule and is rejected.
     }
 }
-fn part2(input: &str) -> usize {
-    let mut strparts = input.trim().split("\n\n");
-    let mut map: HashMap<&str, &Workflow> = HashMap::new();
-    let workflows = strparts
-        .next()
-        .unwrap()
-        .lines()
-        .map(Workflow::from)
+
+/// A disjoint box of accepted ratings, with the rules that fired on the way
+/// to `A` for each of the boxes merged into it.
+#[derive(Debug, Clone)]
+struct AcceptedBox {
+    range: PartRange,
+    chains: Vec<Vec<Step>>,
+}
+
+fn accepted_ranges(system: &System) -> Vec<AcceptedBox> {
+    let mut ranges = Vec::new();
+    do_part2(
+        &system.map(),
+        &system.categories,
+        "in",
+        system.full_range(),
+        &mut Vec::new(),
+        &mut ranges,
+    );
+    ranges
+}
+
+fn accepted_volume(system: &System) -> usize {
+    accepted_ranges(system)
+        .iter()
+        .map(|b| b.range.volume())
+        .sum()
+}
+
+/// Accepted boxes with neighbours that differ along a single category joined
+/// together, sorted by their lower corner.
+fn accepted_boxes(system: &System) -> Vec<AcceptedBox> {
+    let mut boxes = accepted_ranges(system);
+    loop {
+        let before = boxes.len();
+        for dim in 0..system.categories.len() {
+            boxes = merge_along(boxes, dim);
+        }
+        if boxes.len() == before {
+            break;
+        }
+    }
+    let corner = |b: &AcceptedBox| {
+        b.range
+            .bounds
+            .iter()
+            .map(|r| *r.start())
+            .collect::<Vec<_>>()
+    };
+    boxes.sort_by_key(corner);
+    boxes
+}
+
+fn merge_along(boxes: Vec<AcceptedBox>, dim: usize) -> Vec<AcceptedBox> {
+    let mut keys: HashMap<Vec<(usize, usize)>, usize> = HashMap::new();
+    let mut groups: Vec<Vec<AcceptedBox>> = Vec::new();
+    for accepted in boxes {
+        let key = accepted
+            .range
+            .bounds
+            .iter()
+            .enumerate()
+            .filter(|&(d, _)| d != dim)
+            .map(|(_, r)| (*r.start(), *r.end()))
+            .collect::<Vec<_>>();
+        let group = *keys.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(accepted);
+    }
+    let mut merged = Vec::new();
+    for mut group in groups {
+        group.sort_by_key(|b| *b.range.get(dim).start());
+        let mut current: Option<AcceptedBox> = None;
+        for next in group {
+            current = match current {
+                Some(mut last)
+                    if *last.range.get(dim).end() + 1 == *next.range.get(dim).start() =>
+                {
+                    let mut dims = last.range.bounds.iter().cloned().collect::<Vec<_>>();
+                    dims[dim] = *dims[dim].start()..=*next.range.get(dim).end();
+                    last.range.bounds = IntervalBox::new(dims).unwrap();
+                    last.chains.extend(next.chains);
+                    Some(last)
+                }
+                Some(last) => {
+                    merged.push(last);
+                    Some(next)
+                }
+                None => Some(next),
+            };
+        }
+        merged.extend(current);
+    }
+    merged
+}
+
+/// One row per accepted box: the inclusive bounds of each category, the
+/// number of rating combinations, and the rule chains separated by ` | `.
+fn write_csv<W: Write>(
+    boxes: &[AcceptedBox],
+    categories: &Categories,
+    out: &mut W,
+) -> io::Result<()> {
+    let header = categories
+        .names
+        .iter()
+        .flat_map(|name| [format!("{}_min", name), format!("{}_max", name)])
         .collect::<Vec<_>>();
-    for workflow in workflows.iter() {
-        map.insert(&workflow.name, &workflow);
+    writeln!(out, "{},count,rules", header.join(","))?;
+    for accepted in boxes {
+        let bounds = accepted
+            .range
+            .bounds
+            .iter()
+            .map(|r| format!("{},{}", r.start(), r.end()))
+            .collect::<Vec<_>>();
+        let chains = accepted
+            .chains
+            .iter()
+            .map(|chain| {
+                chain
+                    .iter()
+                    .map(Step::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            })
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "{},{},\"{}\"",
+            bounds.join(","),
+            accepted.range.volume(),
+            chains.join(" | ").replace('"', "\"\"")
+        )?;
     }
-    let mut ranges = Vec::new();
-    do_part2(&map, "in", PartRange::default(), &mut ranges);
-    let mut sum = 0;
-    for range in ranges {
-        let x = range.x.size_hint().1.unwrap();
-        let m = range.m.size_hint().1.unwrap();
-        let a = range.a.size_hint().1.unwrap();
-        let s = range.s.size_hint().1.unwrap();
-        sum += x * m * a * s;
+    Ok(())
+}
+
+fn part2(input: &str) -> usize {
+    accepted_volume(&parse(input))
+}
+
+impl Condition {
+    /// The ratings within `bounds` that satisfy the condition.
+    fn ratings(&self, bounds: &RangeInclusive<usize>) -> IntervalSet<usize> {
+        let (lo, hi) = (*bounds.start(), *bounds.end());
+        let value = self.value;
+        let range = match self.operator {
+            Operator::GreaterThan => value + 1..=hi,
+            Operator::AtLeast => value..=hi,
+            Operator::LessThan if value == 0 => return IntervalSet::new(),
+            Operator::LessThan => lo..=value - 1,
+            Operator::AtMost => lo..=value,
+            Operator::Equal => value..=value,
+        };
+        IntervalSet::from(range).intersection(&IntervalSet::from(bounds.clone()))
+    }
+
+    /// Writes `ratings` as a single condition on `key`. `Some(None)` means the
+    /// ratings cover all of `bounds`, so no condition is needed.
+    fn covering(
+        key: &str,
+        ratings: &IntervalSet<usize>,
+        bounds: &RangeInclusive<usize>,
+    ) -> Option<Option<Condition>> {
+        let mut ranges = ratings.iter();
+        let range = ranges.next()?;
+        if ranges.next().is_some() {
+            return None;
+        }
+        let (start, end) = (*range.start(), *range.end());
+        let (operator, value) = if range == bounds {
+            return Some(None);
+        } else if start == *bounds.start() {
+            (Operator::LessThan, end + 1)
+        } else if end == *bounds.end() {
+            (Operator::GreaterThan, start - 1)
+        } else if start == end {
+            (Operator::Equal, start)
+        } else {
+            return None;
+        };
+        Some(Some(Condition {
+            key: key.to_string(),
+            operator,
+            value,
+        }))
+    }
+}
+
+impl System {
+    /// Simplifies the workflows without changing which parts they accept
+    /// within the rating bounds, repeating until nothing changes.
+    fn optimise(&mut self) {
+        let mut workflows = std::mem::take(&mut self.workflows);
+        let full = self.full_range();
+        loop {
+            let mut changed = false;
+            for workflow in &mut workflows {
+                changed |= prune(&mut workflow.rules, &full, &self.categories);
+                changed |= merge(&mut workflow.rules, &self.bounds);
+            }
+            changed |= inline(&mut workflows);
+            if !changed {
+                break;
+            }
+        }
+        self.workflows = workflows;
+    }
+}
+
+/// Drops rules that no rating can reach after the rules before them, and
+/// turns a rule that catches everything left into the fallback.
+fn prune(rules: &mut Vec<Rule>, full: &PartRange, categories: &Categories) -> bool {
+    let mut changed = false;
+    let mut remaining = vec![full.clone()];
+    let mut kept = Vec::with_capacity(rules.len());
+    for mut rule in rules.drain(..) {
+        let mut fires = false;
+        let mut rest = Vec::new();
+        for range in remaining {
+            let (matched, inverse) = range.with_rule(&rule, categories);
+            fires |= matched.is_some();
+            rest.extend(inverse);
+        }
+        remaining = rest;
+        if !fires {
+            changed = true;
+            continue;
+        }
+        if remaining.is_empty() && rule.condition.is_some() {
+            rule.condition = None;
+            changed = true;
+        }
+        kept.push(rule);
+        if remaining.is_empty() {
+            break;
+        }
+    }
+    if kept.is_empty() {
+        // A workflow still needs a rule to be valid syntax.
+        kept.push(Rule {
+            condition: None,
+            operation: Operation::Rejected,
+        });
+    }
+    *rules = kept;
+    changed
+}
+
+/// Combines neighbouring rules with the same target, either into the
+/// fallback after them or into one condition on the same category.
+fn merge(rules: &mut Vec<Rule>, bounds: &RangeInclusive<usize>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index + 1 < rules.len() {
+        let (first, second) = (&rules[index], &rules[index + 1]);
+        if first.operation != second.operation {
+            index += 1;
+            continue;
+        }
+        let merged = match (&first.condition, &second.condition) {
+            (_, None) => Some(None),
+            (Some(a), Some(b)) if a.key == b.key => {
+                let ratings = a.ratings(bounds).union(&b.ratings(bounds));
+                Condition::covering(&a.key, &ratings, bounds)
+            }
+            _ => None,
+        };
+        match merged {
+            Some(condition) => {
+                rules[index + 1].condition = condition;
+                rules.remove(index);
+                changed = true;
+                index = index.saturating_sub(1);
+            }
+            None => index += 1,
+        }
+    }
+    changed
+}
+
+/// Replaces jumps to workflows with a single unconditional rule by that
+/// rule's target, and splices a workflow used only as another workflow's
+/// fallback into its caller. Inlined workflows are removed; `in` is kept.
+fn inline(workflows: &mut Vec<Workflow>) -> bool {
+    let constant = workflows.iter().find_map(|w| match w.rules.as_slice() {
+        [Rule {
+            condition: None,
+            operation,
+        }] if w.name != "in" && *operation != Operation::ProcessWith(w.name.clone()) => {
+            Some((w.name.clone(), operation.clone()))
+        }
+        _ => None,
+    });
+    if let Some((name, operation)) = constant {
+        let target = Operation::ProcessWith(name.clone());
+        for rule in workflows.iter_mut().flat_map(|w| w.rules.iter_mut()) {
+            if rule.operation == target {
+                rule.operation = operation.clone();
+            }
+        }
+        workflows.retain(|w| w.name != name);
+        return true;
+    }
+
+    let mut uses: HashMap<String, usize> = HashMap::new();
+    for rule in workflows.iter().flat_map(|w| w.rules.iter()) {
+        if let Operation::ProcessWith(name) = &rule.operation {
+            *uses.entry(name.clone()).or_default() += 1;
+        }
+    }
+    let splice = workflows.iter().enumerate().find_map(|(caller, w)| {
+        let last = w.rules.last()?;
+        match &last.operation {
+            Operation::ProcessWith(name)
+                if last.condition.is_none()
+                    && *name != w.name
+                    && name != "in"
+                    && uses[name] == 1 =>
+            {
+                let callee = workflows.iter().position(|w| w.name == *name)?;
+                Some((caller, callee))
+            }
+            _ => None,
+        }
+    });
+    match splice {
+        Some((caller, callee)) => {
+            let rules = workflows[callee].rules.clone();
+            let caller_rules = &mut workflows[caller].rules;
+            caller_rules.pop();
+            caller_rules.extend(rules);
+            workflows.remove(callee);
+            true
+        }
+        None => false,
     }
-    sum
+}
+
+#[derive(Debug, PartialEq)]
+enum Finding {
+    MissingWorkflow { workflow: String, target: String },
+    MissingFallback(String),
+    Unreachable(String),
+    Cycle(Vec<String>),
+    DeadRule { workflow: String, rule: usize },
+    Constant { workflow: String, accepted: bool },
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Finding::MissingWorkflow { workflow, target } => {
+                write!(f, "{} refers to missing workflow {}", workflow, target)
+            }
+            Finding::MissingFallback(workflow) => {
+                write!(
+                    f,
+                    "{} has no fallback rule and rejects what its rules miss",
+                    workflow
+                )
+            }
+            Finding::Unreachable(workflow) => write!(f, "{} is unreachable from in", workflow),
+            Finding::Cycle(cycle) => write!(f, "cycle: {} -> {}", cycle.join(" -> "), cycle[0]),
+            Finding::DeadRule { workflow, rule } => {
+                write!(f, "rule {} of {} can never fire", rule, workflow)
+            }
+            Finding::Constant { workflow, accepted } => match accepted {
+                true => write!(f, "{} always accepts", workflow),
+                false => write!(f, "{} always rejects", workflow),
+            },
+        }
+    }
+}
+
+/// What happened while pushing rating boxes through the workflows.
+#[derive(Debug, Default)]
+struct Flow<'a> {
+    entered: HashSet<&'a str>,
+    fired: HashSet<(&'a str, usize)>,
+    accepted: bool,
+    rejected: bool,
+    /// Set when a box hit a missing workflow or went round a cycle.
+    open: bool,
+}
+
+fn flow<'a>(
+    map: &HashMap<&str, &'a Workflow>,
+    categories: &Categories,
+    key: &str,
+    current: PartRange,
+    path: &mut Vec<&'a str>,
+    acc: &mut Flow<'a>,
+) {
+    let workflow = match map.get(key) {
+        Some(workflow) if !path.contains(&workflow.name.as_str()) => *workflow,
+        _ => {
+            acc.open = true;
+            return;
+        }
+    };
+    acc.entered.insert(&workflow.name);
+    path.push(&workflow.name);
+    let mut remaining = vec![current];
+    for (index, rule) in workflow.rules.iter().enumerate() {
+        if remaining.is_empty() {
+            break;
+        }
+        let mut rest = Vec::new();
+        for current in remaining {
+            let (new, inverse) = current.with_rule(rule, categories);
+            rest.extend(inverse);
+            let new = match new {
+                Some(new) => new,
+                None => continue,
+            };
+            acc.fired.insert((&workflow.name, index));
+            match &rule.operation {
+                Operation::Accepted => acc.accepted = true,
+                Operation::Rejected => acc.rejected = true,
+                Operation::ProcessWith(name) => flow(map, categories, name, new, path, acc),
+            }
+        }
+        remaining = rest;
+    }
+    if !remaining.is_empty() {
+        acc.rejected = true;
+    }
+    path.pop();
+}
+
+/// Reports missing references and fallbacks, unreachable workflows, cycles,
+/// rules that no rating box can reach and workflows that could be replaced
+/// by `A` or `R`.
+fn analyse(system: &System) -> Vec<Finding> {
+    let workflows = &system.workflows;
+    let map = system.map();
+    let targets = |workflow: &Workflow| -> Vec<String> {
+        workflow
+            .rules
+            .iter()
+            .filter_map(|rule| match &rule.operation {
+                Operation::ProcessWith(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut findings = Vec::new();
+
+    for workflow in workflows {
+        for target in targets(workflow) {
+            if !map.contains_key(target.as_str()) {
+                findings.push(Finding::MissingWorkflow {
+                    workflow: workflow.name.clone(),
+                    target,
+                });
+            }
+        }
+    }
+    for workflow in workflows {
+        if !workflow.has_fallback() {
+            findings.push(Finding::MissingFallback(workflow.name.clone()));
+        }
+    }
+
+    let mut reachable = HashSet::from(["in".to_string()]);
+    let mut queue = vec!["in".to_string()];
+    while let Some(name) = queue.pop() {
+        if let Some(workflow) = map.get(name.as_str()) {
+            for target in targets(workflow) {
+                if reachable.insert(target.clone()) {
+                    queue.push(target);
+                }
+            }
+        }
+    }
+    for workflow in workflows {
+        if !reachable.contains(&workflow.name) {
+            findings.push(Finding::Unreachable(workflow.name.clone()));
+        }
+    }
+
+    let mut done = HashSet::new();
+    let mut seen_cycles: Vec<Vec<String>> = Vec::new();
+    for workflow in workflows {
+        let mut path = Vec::new();
+        find_cycles(&map, &workflow.name, &mut path, &mut done, &mut seen_cycles);
+    }
+    for cycle in seen_cycles {
+        findings.push(Finding::Cycle(cycle));
+    }
+
+    let mut from_in = Flow::default();
+    flow(
+        &map,
+        &system.categories,
+        "in",
+        system.full_range(),
+        &mut Vec::new(),
+        &mut from_in,
+    );
+    for workflow in workflows {
+        if !from_in.entered.contains(workflow.name.as_str()) {
+            continue;
+        }
+        for rule in 0..workflow.rules.len() {
+            if !from_in.fired.contains(&(workflow.name.as_str(), rule)) {
+                findings.push(Finding::DeadRule {
+                    workflow: workflow.name.clone(),
+                    rule,
+                });
+            }
+        }
+    }
+
+    for workflow in workflows {
+        let mut result = Flow::default();
+        flow(
+            &map,
+            &system.categories,
+            &workflow.name,
+            system.full_range(),
+            &mut Vec::new(),
+            &mut result,
+        );
+        if !result.open && result.accepted != result.rejected {
+            findings.push(Finding::Constant {
+                workflow: workflow.name.clone(),
+                accepted: result.accepted,
+            });
+        }
+    }
+    findings
+}
+
+fn find_cycles(
+    map: &HashMap<&str, &Workflow>,
+    name: &str,
+    path: &mut Vec<String>,
+    done: &mut HashSet<String>,
+    cycles: &mut Vec<Vec<String>>,
+) {
+    if let Some(start) = path.iter().position(|n| n == name) {
+        let cycle = path[start..].to_vec();
+        let mut key = cycle.clone();
+        key.sort();
+        if !cycles.iter().any(|c| {
+            let mut c = c.clone();
+            c.sort();
+            c == key
+        }) {
+            cycles.push(cycle);
+        }
+        return;
+    }
+    if done.contains(name) {
+        return;
+    }
+    let workflow = match map.get(name) {
+        Some(workflow) => workflow,
+        None => return,
+    };
+    path.push(name.to_string());
+    for rule in &workflow.rules {
+        if let Operation::ProcessWith(target) = &rule.operation {
+            find_cycles(map, target, path, done, cycles);
+        }
+    }
+    path.pop();
+    done.insert(name.to_string());
 }
 
 #[test]
 fn test() {
-    let input = "
+    let sample = "
 px{a<2006:qkq,m>2090:A,rfg}
 pv{a>1716:R,A}
 lnx{m>1548:A,A}
@@ -333,6 +1338,286 @@ hdj{m>838:A,pv}
 {x=2461,m=1339,a=466,s=291}
 {x=2127,m=1623,a=2188,s=1013}
 ";
+    let input = sample;
     assert_eq!(part1(input), 19114);
     assert_eq!(part2(input), 167409079868000);
+
+    let system = parse(input);
+    assert_eq!(system.to_string(), input.trim_start());
+    assert_eq!(system.categories.names, vec!["x", "m", "a", "s"]);
+    assert_eq!(interpret(&system.workflows, &system.parts), 19114);
+    let parts = &system.parts;
+    let program = Program::compile(&system.workflows, &system.categories).unwrap();
+    let accepted = parts
+        .iter()
+        .map(|p| program.accepts(&program.ratings(p)))
+        .collect::<Vec<_>>();
+    assert_eq!(accepted, vec![true, false, true, false, true]);
+    assert_eq!(
+        program.names[program
+            .starts
+            .iter()
+            .position(|&s| s == program.entry)
+            .unwrap()],
+        "in"
+    );
+
+    let explanation = program.explain(&parts[0]);
+    assert!(explanation.accepted);
+    assert_eq!(
+        explanation.to_string(),
+        "in: else -> qqz; qqz: s>2770 -> qs; qs: else -> lnx; lnx: m>1548 -> A"
+    );
+    let explanation = program.explain(&parts[1]);
+    assert!(!explanation.accepted);
+    assert_eq!(
+        explanation.to_string(),
+        "in: s<1351 -> px; px: else -> rfg; rfg: s<537 -> gd; gd: else -> R"
+    );
+    assert_eq!(
+        explanation.steps[1],
+        Step {
+            workflow: "px".to_string(),
+            rule: Some(2),
+            condition: "else".to_string(),
+            target: "rfg".to_string()
+        }
+    );
+
+    let findings = analyse(&system);
+    assert_eq!(
+        findings,
+        vec![
+            Finding::Constant {
+                workflow: "lnx".to_string(),
+                accepted: true
+            },
+            Finding::Constant {
+                workflow: "qs".to_string(),
+                accepted: true
+            },
+            Finding::Constant {
+                workflow: "gd".to_string(),
+                accepted: false
+            },
+        ]
+    );
+    assert_eq!(findings[2].to_string(), "gd always rejects");
+
+    let system = parse(
+        "
+in{x<100:a,x<50:R,b}
+a{m>10:A,A}
+b{s<5:gone,c}
+c{x>0:b,R}
+orphan{A}
+",
+    );
+    let findings = analyse(&system);
+    assert_eq!(
+        findings,
+        vec![
+            Finding::MissingWorkflow {
+                workflow: "b".to_string(),
+                target: "gone".to_string()
+            },
+            Finding::Unreachable("orphan".to_string()),
+            Finding::Cycle(vec!["b".to_string(), "c".to_string()]),
+            Finding::DeadRule {
+                workflow: "in".to_string(),
+                rule: 1
+            },
+            Finding::DeadRule {
+                workflow: "c".to_string(),
+                rule: 1
+            },
+            Finding::Constant {
+                workflow: "a".to_string(),
+                accepted: true
+            },
+            Finding::Constant {
+                workflow: "orphan".to_string(),
+                accepted: true
+            },
+        ]
+    );
+    assert_eq!(findings[2].to_string(), "cycle: b -> c -> b");
+
+    // Without a fallback, parts that match no rule are rejected by every engine.
+    let system = parse("in{x>10:A}\nb{A}\n\n{x=1}\n{x=11}");
+    assert_eq!(accepted_sum(&system), 11);
+    assert_eq!(interpret(&system.workflows, &system.parts), 11);
+    assert_eq!(accepted_volume(&system), 3990);
+    let program = Program::compile(&system.workflows, &system.categories).unwrap();
+    assert_eq!(program.starts, vec![0, 2]);
+    let explanation = program.explain(&system.parts[0]);
+    assert!(!explanation.accepted);
+    assert_eq!(explanation.to_string(), "in: no rule matched -> R");
+    assert_eq!(explanation.steps[0].rule, None);
+    let findings = analyse(&system);
+    assert_eq!(
+        findings,
+        vec![
+            Finding::MissingFallback("in".to_string()),
+            Finding::Unreachable("b".to_string()),
+            Finding::Constant {
+                workflow: "b".to_string(),
+                accepted: true
+            },
+        ]
+    );
+    assert_eq!(
+        findings[0].to_string(),
+        "in has no fallback rule and rejects what its rules miss"
+    );
+
+    let system = parse("in{x>10:nope,A}");
+    assert_eq!(
+        Program::compile(&system.workflows, &system.categories).unwrap_err(),
+        "unknown workflow nope"
+    );
+
+    let custom = "
+in{speed>=5:fast,A}
+fast{speed==7:R,weight<=2:A,R}
+
+{speed=7,weight=1}
+{speed=5,weight=2}
+{speed=1,weight=9}
+";
+    let system = parse(custom).with_bounds(1..=10);
+    assert_eq!(system.categories.names, vec!["speed", "weight"]);
+    assert_eq!(accepted_sum(&system), 7 + 10);
+    assert_eq!(interpret(&system.workflows, &system.parts), 7 + 10);
+    // speed < 5 with any weight, or speed in 5..=10 except 7 with weight <= 2.
+    assert_eq!(accepted_volume(&system), 4 * 10 + 5 * 2);
+    let program = Program::compile(&system.workflows, &system.categories).unwrap();
+    let brute_force = (1..=10)
+        .flat_map(|speed| (1..=10).map(move |weight| [speed, weight]))
+        .filter(|ratings| program.accepts(ratings))
+        .count();
+    assert_eq!(brute_force, 50);
+    assert_eq!(
+        program.explain(&system.parts[0]).to_string(),
+        "in: speed>=5 -> fast; fast: speed==7 -> R"
+    );
+    assert_eq!(system.parts[1].to_string(), "{speed=5,weight=2}");
+    assert!(analyse(&system).is_empty());
+
+    // The optimised workflows must accept exactly the same rating boxes.
+    let same_accepted = |a: &System, b: &System| {
+        let (a, b) = (accepted_ranges(a), accepted_ranges(b));
+        let volume = |boxes: &[AcceptedBox]| boxes.iter().map(|b| b.range.volume()).sum::<usize>();
+        let shared = a
+            .iter()
+            .flat_map(|x| {
+                b.iter()
+                    .filter_map(|y| x.range.bounds.intersection(&y.range.bounds))
+            })
+            .map(|bounds| bounds.volume())
+            .sum::<usize>();
+        volume(&a) == shared && volume(&a) == volume(&b)
+    };
+    for input in [sample, custom] {
+        let original = parse(input);
+        let mut optimised = parse(input);
+        optimised.optimise();
+        assert!(same_accepted(&original, &optimised));
+        assert_eq!(accepted_sum(&optimised), accepted_sum(&original));
+        assert!(optimised.workflows.len() <= original.workflows.len());
+    }
+    let mut optimised = parse(sample);
+    optimised.optimise();
+    optimised.parts.clear();
+    assert_eq!(
+        optimised.to_string(),
+        "px{a<2006:qkq,m>2090:A,s<537:R,x>2440:R,A}
+qkq{x<1416:A,x>2662:A,R}
+in{s<1351:px,s>2770:A,m<1801:hdj,R}
+hdj{m>838:A,a>1716:R,A}
+"
+    );
+    let mut merged = parse("in{x>10:A,x==10:A,m<5:R,m<=7:R,a}\na{b}\nb{s>1:A,R}");
+    merged.optimise();
+    assert_eq!(merged.to_string(), "in{x>9:A,m<8:R,s>1:A,R}\n");
+    for (input, bounds) in [("in{x>4000:A}", 1..=4000), ("in{x==7:R}", 1..=6)] {
+        let mut dead = parse(input).with_bounds(bounds);
+        dead.optimise();
+        let printed = dead.to_string();
+        assert_eq!(printed, "in{R}\n");
+        let reparsed = parse(&printed);
+        assert_eq!(reparsed.to_string(), printed);
+        assert!(Program::compile(&reparsed.workflows, &reparsed.categories).is_ok());
+    }
+
+    let boxes = accepted_boxes(&parse(sample));
+    assert_eq!(
+        boxes.iter().map(|b| b.range.volume()).sum::<usize>(),
+        167409079868000
+    );
+    assert!(boxes.len() <= accepted_ranges(&parse(sample)).len());
+
+    let system = parse(custom).with_bounds(1..=10);
+    let mut csv = Vec::new();
+    write_csv(&accepted_boxes(&system), &system.categories, &mut csv).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "speed_min,speed_max,weight_min,weight_max,count,rules
+1,4,1,10,40,\"in: else -> A\"
+5,6,1,2,4,\"in: speed>=5 -> fast; fast: weight<=2 -> A\"
+8,10,1,2,6,\"in: speed>=5 -> fast; fast: weight<=2 -> A\"
+"
+    );
+
+    let system = parse("in{x<5:a,x<8:A,R}\na{A}").with_bounds(1..=10);
+    let boxes = accepted_boxes(&system);
+    assert_eq!(boxes.len(), 1);
+    assert_eq!(boxes[0].range.get(0), &(1..=7));
+    assert_eq!(
+        boxes[0]
+            .chains
+            .iter()
+            .map(|chain| chain.iter().map(Step::to_string).collect::<Vec<_>>())
+            .collect::<Vec<_>>(),
+        vec![vec!["in: x<5 -> a", "a: else -> A"], vec!["in: x<8 -> A"]]
+    );
+}
+
+/// Times the compiled program against the interpreter on the real input:
+/// `cargo test --release day19::bench -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench() {
+    let input = std::fs::read_to_string("input/day19.txt").unwrap();
+    let system = parse(&input);
+    let (workflows, parts) = (&system.workflows, &system.parts);
+    let rounds = 1000;
+
+    let start = std::time::Instant::now();
+    let mut interpreted = 0;
+    for _ in 0..rounds {
+        interpreted = interpret(workflows, parts);
+    }
+    let interpreter_time = start.elapsed();
+
+    let program = Program::compile(workflows, &system.categories).unwrap();
+    let start = std::time::Instant::now();
+    let mut compiled = 0;
+    for _ in 0..rounds {
+        // Converting the ratings is part of the cost, as the interpreter pays
+        // for its lookups by name.
+        compiled = parts
+            .iter()
+            .filter(|part| program.accepts(&program.ratings(part)))
+            .map(Part::value)
+            .sum();
+    }
+    let compiled_time = start.elapsed();
+
+    assert_eq!(interpreted, compiled);
+    println!(
+        "interpreter: {:?}/round, compiled: {:?}/round",
+        interpreter_time / rounds,
+        compiled_time / rounds
+    );
 }
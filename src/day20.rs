@@ -1,4 +1,8 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    io::{self, Write},
+};
 
 pub fn solve() {
     let input = std::fs::read_to_string("input/day20.txt").unwrap();
@@ -13,205 +17,954 @@ enum Pulse {
     Low,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-enum ModuleKind {
-    Broadcaster,
-    FlipFlop,
-    Conjunction,
+/// The behaviour of one module kind. Pulses are `true` for high.
+trait Behavior: std::fmt::Debug {
+    /// A display name only; use `is_sink` and `is_conjunction` to dispatch.
+    fn kind(&self) -> &'static str;
+    /// Sinks sort after every other module and are left out of snapshots.
+    fn is_sink(&self) -> bool {
+        false
+    }
+    /// Conjunctions fire low once every input is high, which the cycle
+    /// search in `presses_until_low` relies on.
+    fn is_conjunction(&self) -> bool {
+        false
+    }
+    /// Handles a pulse from the input at `slot` and returns the pulse to send, if any.
+    fn receive(&mut self, high: bool, slot: usize) -> Option<bool>;
+    /// Called with the number of inputs when the network is built or reset.
+    fn connect(&mut self, inputs: usize) {}
+    /// The level the module currently drives on its outputs.
+    fn level(&self) -> bool {
+        false
+    }
+    fn save(&self, snapshot: &mut Snapshot) {}
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {}
+    fn shape(&self) -> &'static str {
+        "box"
+    }
 }
 
 #[derive(Debug)]
-struct Module {
-    label: String,
+struct Broadcaster;
+
+impl Behavior for Broadcaster {
+    fn kind(&self) -> &'static str {
+        "broadcaster"
+    }
+    fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+        Some(high)
+    }
+    fn shape(&self) -> &'static str {
+        "doubleoctagon"
+    }
+}
+
+#[derive(Debug)]
+struct Sink;
+
+impl Behavior for Sink {
+    fn kind(&self) -> &'static str {
+        "sink"
+    }
+    fn is_sink(&self) -> bool {
+        true
+    }
+    fn receive(&mut self, _: bool, _: usize) -> Option<bool> {
+        None
+    }
+    fn shape(&self) -> &'static str {
+        "circle"
+    }
+}
+
+#[derive(Debug, Default)]
+struct FlipFlop {
     is_on: bool,
-    kind: ModuleKind,
-    con_state: HashMap<String, Pulse>,
-    outputs: Vec<String>,
 }
 
-impl Module {
-    fn receive(&mut self, pulse: &Pulse, from: String) -> Vec<(String, Pulse)> {
-        match self.kind {
-            ModuleKind::Broadcaster => self.broadcast(pulse),
-            ModuleKind::FlipFlop => self.flip_flop(pulse),
-            ModuleKind::Conjunction => self.conjunction(pulse, from),
+impl Behavior for FlipFlop {
+    fn kind(&self) -> &'static str {
+        "flip-flop"
+    }
+    fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+        if high {
+            return None;
         }
+        self.is_on = !self.is_on;
+        Some(self.is_on)
     }
-    fn broadcast(&self, pulse: &Pulse) -> Vec<(String, Pulse)> {
-        self.outputs
-            .iter()
-            .map(|label| (label.clone(), pulse.clone()))
-            .collect()
+    fn level(&self) -> bool {
+        self.is_on
     }
-    fn flip_flop(&mut self, pulse: &Pulse) -> Vec<(String, Pulse)> {
-        let mut outputs = Vec::new();
-        match pulse {
-            Pulse::Low => {
-                let send_pulse = match self.is_on {
-                    false => {
-                        self.is_on = true;
-                        Pulse::High
-                    }
-                    true => {
-                        self.is_on = false;
-                        Pulse::Low
-                    }
-                };
-                for label in &self.outputs {
-                    outputs.push((label.clone(), send_pulse.clone()));
-                }
+    fn save(&self, snapshot: &mut Snapshot) {
+        snapshot.push(self.is_on);
+    }
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {
+        self.is_on = snapshot.get(*index);
+        *index += 1;
+    }
+}
+
+/// Remembers the last pulse from each input as a bitset.
+#[derive(Debug, Default)]
+struct Conjunction {
+    memory: Vec<u64>,
+    inputs: usize,
+    high_inputs: usize,
+}
+
+impl Behavior for Conjunction {
+    fn kind(&self) -> &'static str {
+        "conjunction"
+    }
+    fn is_conjunction(&self) -> bool {
+        true
+    }
+    fn receive(&mut self, high: bool, slot: usize) -> Option<bool> {
+        let (word, mask) = (slot / 64, 1 << (slot % 64));
+        if high != (self.memory[word] & mask != 0) {
+            self.memory[word] ^= mask;
+            match high {
+                true => self.high_inputs += 1,
+                false => self.high_inputs -= 1,
+            }
+        }
+        Some(self.level())
+    }
+    fn connect(&mut self, inputs: usize) {
+        self.memory = vec![0; inputs.div_ceil(64)];
+        self.inputs = inputs;
+        self.high_inputs = 0;
+    }
+    fn level(&self) -> bool {
+        self.high_inputs != self.inputs
+    }
+    fn save(&self, snapshot: &mut Snapshot) {
+        for slot in 0..self.inputs {
+            snapshot.push(self.memory[slot / 64] & (1 << (slot % 64)) != 0);
+        }
+    }
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {
+        self.connect(self.inputs);
+        for slot in 0..self.inputs {
+            if snapshot.get(*index) {
+                self.memory[slot / 64] |= 1 << (slot % 64);
+                self.high_inputs += 1;
             }
-            Pulse::High => (),
+            *index += 1;
         }
-        outputs
     }
+    fn shape(&self) -> &'static str {
+        "diamond"
+    }
+}
 
-    fn conjunction(&mut self, pulse: &Pulse, from: String) -> Vec<(String, Pulse)> {
-        let mut outputs = Vec::new();
-        let mut send = Pulse::High;
-        self.con_state.insert(from, pulse.clone());
-        if self.con_state.values().all(|p| *p == Pulse::High) {
-            send = Pulse::Low;
-        };
-        for label in &self.outputs {
-            outputs.push((label.clone(), send.clone()));
+/// Sends the opposite of every pulse it receives.
+#[derive(Debug, Default)]
+struct Inverter {
+    level: bool,
+}
+
+impl Behavior for Inverter {
+    fn kind(&self) -> &'static str {
+        "inverter"
+    }
+    fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+        self.level = !high;
+        Some(self.level)
+    }
+    fn level(&self) -> bool {
+        self.level
+    }
+    fn save(&self, snapshot: &mut Snapshot) {
+        snapshot.push(self.level);
+    }
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {
+        self.level = snapshot.get(*index);
+        *index += 1;
+    }
+    fn shape(&self) -> &'static str {
+        "invtriangle"
+    }
+}
+
+/// Counts low pulses modulo `modulus`, sending high when it wraps to zero and
+/// low otherwise.
+#[derive(Debug)]
+struct Counter {
+    modulus: usize,
+    count: usize,
+}
+
+impl Counter {
+    fn width(&self) -> usize {
+        (usize::BITS - (self.modulus - 1).leading_zeros()) as usize
+    }
+}
+
+impl Behavior for Counter {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+    fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+        if high {
+            return None;
         }
-        outputs
+        self.count = (self.count + 1) % self.modulus;
+        Some(self.count == 0)
+    }
+    fn level(&self) -> bool {
+        self.count == 0
+    }
+    fn save(&self, snapshot: &mut Snapshot) {
+        for bit in 0..self.width() {
+            snapshot.push(self.count & (1 << bit) != 0);
+        }
+    }
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {
+        self.count = 0;
+        for bit in 0..self.width() {
+            if snapshot.get(*index) {
+                self.count |= 1 << bit;
+            }
+            *index += 1;
+        }
+    }
+    fn shape(&self) -> &'static str {
+        "octagon"
     }
 }
 
-impl From<&str> for Module {
-    fn from(value: &str) -> Self {
-        let mut parts = value.split(" -> ");
-        let mut label_chars = parts.next().unwrap().chars().peekable();
-        let kind = match label_chars.peek() {
-            Some('%') => {
-                label_chars.next();
-                ModuleKind::FlipFlop
-            }
-            Some('&') => {
-                label_chars.next();
-                ModuleKind::Conjunction
-            }
-            _ => ModuleKind::Broadcaster,
+/// Holds one pulse: each pulse it receives sends the previously held one.
+#[derive(Debug, Default)]
+struct Latch {
+    held: bool,
+}
+
+impl Behavior for Latch {
+    fn kind(&self) -> &'static str {
+        "latch"
+    }
+    fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+        let send = self.held;
+        self.held = high;
+        Some(send)
+    }
+    fn level(&self) -> bool {
+        self.held
+    }
+    fn save(&self, snapshot: &mut Snapshot) {
+        snapshot.push(self.held);
+    }
+    fn load(&mut self, snapshot: &Snapshot, index: &mut usize) {
+        self.held = snapshot.get(*index);
+        *index += 1;
+    }
+    fn shape(&self) -> &'static str {
+        "house"
+    }
+}
+
+type Constructor = fn(Option<&str>) -> Result<Box<dyn Behavior>, String>;
+
+/// Maps label prefixes such as `%` to module kinds. A line without a known
+/// prefix is the broadcaster when labelled `broadcaster`, or a sink when it has
+/// no `->`. Parameters follow the label after a colon, as in `#tick:3`.
+struct ModuleRegistry {
+    prefixes: Vec<(String, Constructor)>,
+    implicit_sinks: bool,
+}
+
+impl Default for ModuleRegistry {
+    fn default() -> Self {
+        let mut registry = ModuleRegistry {
+            prefixes: Vec::new(),
+            implicit_sinks: true,
         };
-        let outputs: Vec<String> = parts
-            .next()
-            .unwrap()
-            .split(", ")
-            .map(|s| s.to_string())
-            .collect();
-        let con_state = HashMap::new();
-        // if kind == ModuleKind::Conjunction {
-        //     for label in outputs.iter() {
-        //         con_state.insert(label.clone(), Pulse::Low);
-        //     }
-        // }
-        Module {
-            label: label_chars.collect::<String>(),
-            is_on: false,
-            kind,
-            con_state,
+        registry.register("%", |_| Ok(Box::<FlipFlop>::default()));
+        registry.register("&", |_| Ok(Box::<Conjunction>::default()));
+        registry.register("!", |_| Ok(Box::<Inverter>::default()));
+        registry.register("~", |_| Ok(Box::<Latch>::default()));
+        registry.register("#", |arg| {
+            let modulus = arg
+                .ok_or("counter needs a modulus")?
+                .parse::<usize>()
+                .map_err(|err| err.to_string())?;
+            if modulus == 0 {
+                return Err("counter modulus must be positive".to_string());
+            }
+            Ok(Box::new(Counter { modulus, count: 0 }))
+        });
+        registry
+    }
+}
+
+impl ModuleRegistry {
+    fn register(&mut self, prefix: &str, constructor: Constructor) {
+        self.prefixes.retain(|(p, _)| p != prefix);
+        self.prefixes.push((prefix.to_string(), constructor));
+        self.prefixes
+            .sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+    }
+
+    /// Requires every module that receives pulses to be declared.
+    fn strict(mut self) -> Self {
+        self.implicit_sinks = false;
+        self
+    }
+
+    fn parse_line(&self, line: &str) -> Result<Module, ParseError> {
+        let (head, outputs) = match line.split_once(" -> ") {
+            Some((head, outputs)) => (head, outputs.split(", ").map(String::from).collect()),
+            None => (line, Vec::new()),
+        };
+        let prefixed = self
+            .prefixes
+            .iter()
+            .find(|(prefix, _)| head.starts_with(prefix.as_str()));
+        let (name, behavior) = match prefixed {
+            Some((prefix, constructor)) => {
+                let name = &head[prefix.len()..];
+                let (name, arg) = match name.split_once(':') {
+                    Some((name, arg)) => (name, Some(arg)),
+                    None => (name, None),
+                };
+                let behavior = constructor(arg)
+                    .map_err(|err| ParseError::BadArgument(name.to_string(), err))?;
+                (name, behavior)
+            }
+            None if head == "broadcaster" => (head, Box::new(Broadcaster) as Box<dyn Behavior>),
+            None if outputs.is_empty() => (head, Box::new(Sink) as Box<dyn Behavior>),
+            None => return Err(ParseError::UnknownKind(line.to_string())),
+        };
+        Ok(Module {
+            label: name.to_string(),
+            behavior,
             outputs,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ParseError {
+    UnknownKind(String),
+    BadArgument(String, String),
+    Duplicate(String),
+    Undeclared(String),
+    NoBroadcaster,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownKind(line) => write!(f, "unknown module kind in `{}`", line),
+            ParseError::BadArgument(label, err) => write!(f, "bad argument for {}: {}", label, err),
+            ParseError::Duplicate(label) => write!(f, "{} is declared twice", label),
+            ParseError::Undeclared(label) => write!(f, "{} is used but never declared", label),
+            ParseError::NoBroadcaster => write!(f, "there is no broadcaster"),
         }
     }
 }
 
+#[derive(Debug)]
+struct Module {
+    label: String,
+    behavior: Box<dyn Behavior>,
+    outputs: Vec<String>,
+}
+
+const BUTTON: usize = usize::MAX;
+
+/// The module network with labels interned to dense ids. Modules get the ids
+/// `0..module_count` in label order, followed by sinks such as `rx`.
 #[derive(Debug)]
 struct ModuleMap {
-    modules: HashMap<String, Module>,
-    queue: VecDeque<(String, String, Pulse)>,
+    labels: Vec<String>,
+    ids: HashMap<String, usize>,
+    broadcaster: usize,
+    modules: Vec<Box<dyn Behavior>>,
+    module_count: usize,
+    outputs: Vec<Vec<usize>>,
+    inputs: Vec<Vec<usize>>,
+    /// For every edge in `outputs`, the sender's position among the receiver's inputs.
+    slots: Vec<Vec<usize>>,
+    queue: VecDeque<(usize, usize, usize, bool)>,
+    high_count: usize,
+    low_count: usize,
+    presses: usize,
+    trace: Option<Trace>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct TraceEvent {
+    press: usize,
+    seq: usize,
+    from: String,
+    to: String,
+    pulse: Pulse,
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}: {} -{:?}-> {}",
+            self.press, self.seq, self.from, self.pulse, self.to
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct TraceFilter {
+    labels: Option<Vec<String>>,
+    pulse: Option<Pulse>,
+}
+
+impl TraceFilter {
+    fn labels(labels: &[&str]) -> Self {
+        TraceFilter {
+            labels: Some(labels.iter().map(|l| l.to_string()).collect()),
+            pulse: None,
+        }
+    }
+
+    fn pulse(mut self, pulse: Pulse) -> Self {
+        self.pulse = Some(pulse);
+        self
+    }
+
+    fn matches(&self, event: &TraceEvent) -> bool {
+        let label_match = match &self.labels {
+            Some(labels) => labels.iter().any(|l| *l == event.from || *l == event.to),
+            None => true,
+        };
+        let pulse_match = match &self.pulse {
+            Some(pulse) => *pulse == event.pulse,
+            None => true,
+        };
+        label_match && pulse_match
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Trace {
+    filter: TraceFilter,
+    start: usize,
+    presses: usize,
+    events: Vec<TraceEvent>,
+}
+
+impl Trace {
+    fn filtered<'a>(&'a self, filter: &'a TraceFilter) -> impl Iterator<Item = &'a TraceEvent> {
+        self.events.iter().filter(|event| filter.matches(event))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Divergence {
+    index: usize,
+    expected: Option<TraceEvent>,
+    actual: Option<TraceEvent>,
+}
+
+/// Packed flip-flop flags and conjunction memories, in label order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Snapshot {
+    len: usize,
+    bits: Vec<u64>,
+}
+
+impl Snapshot {
+    fn new() -> Self {
+        Snapshot {
+            len: 0,
+            bits: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, bit: bool) {
+        if self.len.is_multiple_of(64) {
+            self.bits.push(0);
+        }
+        if bit {
+            self.bits[self.len / 64] |= 1 << (self.len % 64);
+        }
+        self.len += 1;
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Cycle {
+    /// Presses made by `find_cycle` before the cycle begins.
+    start: usize,
+    length: usize,
     high_count: usize,
     low_count: usize,
 }
 
 impl From<&str> for ModuleMap {
     fn from(value: &str) -> Self {
-        let mut modules: HashMap<String, Module> = HashMap::new();
-        value
-            .trim()
-            .lines()
-            .map(|line| Module::from(line))
-            .for_each(|module| {
-                modules.insert(module.label.clone(), module);
-            });
-        let queue = VecDeque::new();
-        ModuleMap {
-            modules,
-            queue,
-            high_count: 0,
-            low_count: 0,
+        match ModuleMap::parse(value, &ModuleRegistry::default()) {
+            Ok(map) => map,
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum CycleError {
+    NoFeeder(String),
+    MultipleFeeders(String, Vec<String>),
+    NotConjunction(String),
+    NotACycle {
+        label: String,
+        first: usize,
+        second: usize,
+    },
+    Timeout(usize),
+}
+
+impl Display for CycleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CycleError::NoFeeder(target) => write!(f, "no module sends to {}", target),
+            CycleError::MultipleFeeders(target, feeders) => {
+                write!(
+                    f,
+                    "{} is fed by {} modules: {}",
+                    target,
+                    feeders.len(),
+                    feeders.join(", ")
+                )
+            }
+            CycleError::NotConjunction(label) => write!(f, "{} is not a conjunction", label),
+            CycleError::NotACycle {
+                label,
+                first,
+                second,
+            } => write!(
+                f,
+                "{} sent high on presses {} and {}, which is not a cycle from press 0",
+                label, first, second
+            ),
+            CycleError::Timeout(presses) => {
+                write!(f, "no cycle found within {} presses", presses)
+            }
         }
     }
 }
 
 impl ModuleMap {
+    fn parse(input: &str, registry: &ModuleRegistry) -> Result<Self, ParseError> {
+        let mut modules = Vec::new();
+        for line in input.trim().lines() {
+            modules.push(registry.parse_line(line.trim())?);
+        }
+        let mut seen = HashSet::new();
+        if let Some(module) = modules.iter().find(|m| !seen.insert(&m.label)) {
+            return Err(ParseError::Duplicate(module.label.clone()));
+        }
+        modules.sort_by(|a, b| {
+            let sink = |m: &Module| m.behavior.is_sink();
+            (sink(a), &a.label).cmp(&(sink(b), &b.label))
+        });
+        let module_count = modules.iter().filter(|m| !m.behavior.is_sink()).count();
+        let mut undeclared = modules
+            .iter()
+            .flat_map(|m| m.outputs.iter())
+            .filter(|label| !modules.iter().any(|m| m.label == **label))
+            .cloned()
+            .collect::<Vec<_>>();
+        undeclared.sort();
+        undeclared.dedup();
+        if let (false, Some(label)) = (registry.implicit_sinks, undeclared.first()) {
+            return Err(ParseError::Undeclared(label.clone()));
+        }
+        for label in undeclared {
+            modules.push(Module {
+                label,
+                behavior: Box::new(Sink),
+                outputs: Vec::new(),
+            });
+        }
+
+        let labels = modules.iter().map(|m| m.label.clone()).collect::<Vec<_>>();
+        let ids: HashMap<String, usize> = labels
+            .iter()
+            .enumerate()
+            .map(|(id, label)| (label.clone(), id))
+            .collect();
+        let broadcaster = *ids.get("broadcaster").ok_or(ParseError::NoBroadcaster)?;
+        let mut outputs = vec![Vec::new(); labels.len()];
+        let mut inputs = vec![Vec::new(); labels.len()];
+        let mut behaviors = Vec::with_capacity(labels.len());
+        for (id, module) in modules.into_iter().enumerate() {
+            for output in module.outputs {
+                let to = ids[&output];
+                outputs[id].push(to);
+                // A sender listed twice still owns a single memory slot.
+                if !inputs[to].contains(&id) {
+                    inputs[to].push(id);
+                }
+            }
+            behaviors.push(module.behavior);
+        }
+        let slots = outputs
+            .iter()
+            .enumerate()
+            .map(|(from, tos)| {
+                tos.iter()
+                    .map(|&to| inputs[to].iter().position(|&i| i == from).unwrap())
+                    .collect()
+            })
+            .collect();
+
+        let mut map = ModuleMap {
+            labels,
+            ids,
+            broadcaster,
+            modules: behaviors,
+            module_count,
+            outputs,
+            inputs,
+            slots,
+            queue: VecDeque::new(),
+            high_count: 0,
+            low_count: 0,
+            presses: 0,
+            trace: None,
+        };
+        map.reset_con_states();
+        Ok(map)
+    }
+
     fn push_button(&mut self) {
-        let initial = "broadcaster".to_string();
-        let broadcaster = self.modules.get_mut(&initial).unwrap();
-        let pulse = Pulse::Low;
-        self.queue.push_back(("button".to_string(), initial, pulse));
-        while let Some((from, to, pulse)) = self.queue.pop_front() {
-            println!("{} -{:?}-> {}", from, pulse, to);
-            self.inc_pulse(&pulse);
-            let module = match self.modules.get_mut(&to) {
-                Some(module) => module,
+        self.press(None, &mut Vec::new());
+    }
+
+    /// Pushes the button, collecting the ids that sent a high pulse to `watch`.
+    fn press(&mut self, watch: Option<usize>, high_senders: &mut Vec<usize>) {
+        self.presses += 1;
+        if let Some(trace) = &mut self.trace {
+            trace.presses += 1;
+        }
+        self.queue.push_back((BUTTON, self.broadcaster, 0, false));
+        let mut seq = 0;
+        while let Some((from, to, slot, high)) = self.queue.pop_front() {
+            if self.trace.is_some() {
+                self.record(seq, from, to, high);
+            }
+            seq += 1;
+            match high {
+                true => self.high_count += 1,
+                false => self.low_count += 1,
+            }
+            if high && watch == Some(to) {
+                high_senders.push(from);
+            }
+            let send = match self.modules[to].receive(high, slot) {
+                Some(send) => send,
                 None => continue,
             };
-            let outputs = module.receive(&pulse, from);
-            for (label, pulse) in outputs {
-                self.queue.push_back((module.label.clone(), label, pulse));
+            for (&output, &slot) in self.outputs[to].iter().zip(&self.slots[to]) {
+                self.queue.push_back((to, output, slot, send));
             }
         }
     }
 
-    fn send(&mut self, label: &str, pulse: Pulse) {
-        let module = self.modules.get_mut(label).unwrap();
-        module.is_on = match pulse {
-            Pulse::High => true,
-            Pulse::Low => false,
+    fn record(&mut self, seq: usize, from: usize, to: usize, high: bool) {
+        let event = TraceEvent {
+            press: self.presses,
+            seq,
+            from: self.label(from).to_string(),
+            to: self.label(to).to_string(),
+            pulse: match high {
+                true => Pulse::High,
+                false => Pulse::Low,
+            },
         };
+        let trace = self.trace.as_mut().unwrap();
+        if trace.filter.matches(&event) {
+            trace.events.push(event);
+        }
+    }
+
+    fn label(&self, id: usize) -> &str {
+        match id {
+            BUTTON => "button",
+            _ => &self.labels[id],
+        }
+    }
+
+    fn level(&self, id: usize) -> bool {
+        self.modules[id].level()
+    }
+
+    fn start_trace(&mut self, filter: TraceFilter) {
+        self.trace = Some(Trace {
+            filter,
+            start: self.presses,
+            ..Default::default()
+        });
     }
 
-    fn inc_pulse(&mut self, pulse: &Pulse) {
-        match *pulse {
-            Pulse::High => self.high_count += 1,
-            Pulse::Low => self.low_count += 1,
+    fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    /// Presses the button `presses` times and returns the events that match
+    /// `filter`. A trace already in progress records these presses as well.
+    fn press_traced(&mut self, presses: usize, filter: TraceFilter) -> Trace {
+        let previous = self.trace.take();
+        self.start_trace(TraceFilter::default());
+        for _ in 0..presses {
+            self.push_button();
+        }
+        let mut all = std::mem::replace(&mut self.trace, previous).unwrap();
+        if let Some(trace) = &mut self.trace {
+            trace.presses += all.presses;
+            let events = all.events.iter().filter(|e| trace.filter.matches(e));
+            trace.events.extend(events.cloned());
         }
+        all.events.retain(|e| filter.matches(e));
+        all.filter = filter;
+        all
     }
-    fn set_init_con_states(&mut self) {
-        let con_mods = self.con_labels();
-        for con_label in con_mods {
-            let con_inputs = self.get_con_inputs(&con_label);
-            let con = self.modules.get_mut(&con_label).unwrap();
-            for input in con_inputs {
-                con.con_state.insert(input.clone(), Pulse::Low);
+
+    /// Re-runs a trace's presses on this map and reports the first event that
+    /// differs, so the map must be in the state the trace was started from.
+    fn replay(&mut self, trace: &Trace) -> Result<(), Box<Divergence>> {
+        let actual = self.press_traced(trace.presses, trace.filter.clone());
+        let offset = trace.start as isize - actual.start as isize;
+        let max = trace.events.len().max(actual.events.len());
+        for index in 0..max {
+            let expected = trace.events.get(index).cloned();
+            let actual = actual.events.get(index).cloned().map(|mut e| {
+                e.press = (e.press as isize + offset) as usize;
+                e
+            });
+            if expected != actual {
+                return Err(Box::new(Divergence {
+                    index,
+                    expected,
+                    actual,
+                }));
             }
         }
+        Ok(())
+    }
+
+    fn sorted_labels(&self) -> Vec<String> {
+        self.labels[..self.module_count].to_vec()
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.snapshot_of(&self.sorted_labels())
+    }
+
+    fn snapshot_of(&self, labels: &[String]) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        for label in labels {
+            self.modules[self.ids[label]].save(&mut snapshot);
+        }
+        snapshot
+    }
+
+    /// Restores a snapshot taken with `snapshot` from a map with the same modules.
+    fn restore(&mut self, snapshot: &Snapshot) {
+        let mut index = 0;
+        for module in &mut self.modules[..self.module_count] {
+            module.load(snapshot, &mut index);
+        }
+        assert_eq!(index, snapshot.len, "snapshot does not match this network");
     }
-    fn con_labels(&self) -> Vec<String> {
-        let mut labels = Vec::new();
-        for module in self.modules.values() {
-            if module.kind == ModuleKind::Conjunction {
-                labels.push(module.label.clone());
+
+    /// Labels of every module reachable from `root`, including `root` itself.
+    fn subgraph(&self, root: &str) -> Vec<String> {
+        let root = self.ids[root];
+        let mut seen = vec![false; self.labels.len()];
+        seen[root] = true;
+        let mut queue = VecDeque::from([root]);
+        while let Some(id) = queue.pop_front() {
+            for &output in &self.outputs[id] {
+                if !seen[output] && output < self.module_count {
+                    seen[output] = true;
+                    queue.push_back(output);
+                }
             }
         }
-        labels
+        (0..self.labels.len())
+            .filter(|&id| seen[id])
+            .map(|id| self.labels[id].clone())
+            .collect()
     }
-    fn get_con_inputs(&self, label: &str) -> Vec<String> {
-        let mut inputs = Vec::new();
-        for module in self.modules.values() {
-            if module.outputs.contains(&label.to_string()) {
-                inputs.push(module.label.clone());
+
+    /// Pushes the button until the state of `labels` (or of the whole network)
+    /// repeats, and reports where the cycle starts, its length and the pulses
+    /// sent during one period. `start` counts from the state at the call, so
+    /// it does not depend on presses made before a `restore`.
+    fn find_cycle(&mut self, labels: Option<&[String]>, max_presses: usize) -> Option<Cycle> {
+        let labels = match labels {
+            Some(labels) => labels.to_vec(),
+            None => self.sorted_labels(),
+        };
+        let mut seen: HashMap<Snapshot, (usize, usize, usize)> = HashMap::new();
+        for press in 0..=max_presses {
+            let state = self.snapshot_of(&labels);
+            let counts = (press, self.high_count, self.low_count);
+            if let Some((start, high, low)) = seen.insert(state, counts) {
+                return Some(Cycle {
+                    start,
+                    length: press - start,
+                    high_count: self.high_count - high,
+                    low_count: self.low_count - low,
+                });
             }
+            self.push_button();
+        }
+        None
+    }
+
+    /// Renders the network as a Graphviz DOT graph. With `state_after`, the
+    /// button is pushed that many times first and nodes are filled by level.
+    fn render_dot(&mut self, state_after: Option<usize>) -> String {
+        if let Some(presses) = state_after {
+            for _ in 0..presses {
+                self.push_button();
+            }
+        }
+        let mut dot = String::from("digraph modules {\n");
+        for (id, label) in self.labels.iter().enumerate() {
+            let shape = self.modules[id].shape();
+            let style = match (state_after, id < self.module_count, self.level(id)) {
+                (Some(_), true, true) => ", style=filled, fillcolor=gold",
+                (Some(_), true, false) => ", style=filled, fillcolor=lightgrey",
+                _ => "",
+            };
+            dot.push_str(&format!("    \"{}\" [shape={}{}];\n", label, shape, style));
+        }
+        for (id, label) in self.labels.iter().enumerate() {
+            for &output in &self.outputs[id] {
+                dot.push_str(&format!(
+                    "    \"{}\" -> \"{}\";\n",
+                    label, self.labels[output]
+                ));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Writes a Value Change Dump of every module's output level over `presses`
+    /// button presses, with one time step per delivered pulse.
+    fn write_vcd<W: Write>(&mut self, presses: usize, out: &mut W) -> io::Result<()> {
+        let mut levels = (0..self.module_count)
+            .map(|id| self.level(id))
+            .collect::<Vec<_>>();
+
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module circuit $end")?;
+        for id in 0..self.module_count {
+            writeln!(out, "$var wire 1 {} {} $end", vcd_id(id), self.labels[id])?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+        for (id, level) in levels.iter().enumerate() {
+            writeln!(out, "{}{}", *level as u8, vcd_id(id))?;
+        }
+        writeln!(out, "$end")?;
+
+        let trace = self.press_traced(presses, TraceFilter::default());
+        for (time, event) in trace.events.iter().enumerate() {
+            let level = event.pulse == Pulse::High;
+            let id = match self.ids.get(&event.from) {
+                Some(&id) => id,
+                None => continue,
+            };
+            if levels[id] != level {
+                levels[id] = level;
+                writeln!(out, "#{}", time + 1)?;
+                writeln!(out, "{}{}", level as u8, vcd_id(id))?;
+            }
+        }
+        writeln!(out, "#{}", trace.events.len() + 1)?;
+        Ok(())
+    }
+
+    /// Finds the press on which `target` first receives a low pulse, assuming it
+    /// is fed by a single conjunction whose inputs each send high periodically.
+    fn presses_until_low(&mut self, target: &str, max_presses: usize) -> Result<usize, CycleError> {
+        let feeders = self.get_con_inputs(target);
+        let feeder = match &feeders[..] {
+            [] => return Err(CycleError::NoFeeder(target.to_string())),
+            [feeder] => self.ids[feeder],
+            _ => return Err(CycleError::MultipleFeeders(target.to_string(), feeders)),
+        };
+        if !self.modules[feeder].is_conjunction() {
+            return Err(CycleError::NotConjunction(self.labels[feeder].clone()));
+        }
+        let mut hits: HashMap<usize, Vec<usize>> = self.inputs[feeder]
+            .iter()
+            .map(|&id| (id, Vec::new()))
+            .collect();
+        let mut high_senders = Vec::new();
+        for press in 1..=max_presses {
+            high_senders.clear();
+            self.press(Some(feeder), &mut high_senders);
+            for id in &high_senders {
+                // Several high pulses in one press count as a single hit.
+                let presses = hits.get_mut(id).unwrap();
+                if presses.len() < 2 && presses.last() != Some(&press) {
+                    presses.push(press);
+                }
+            }
+            if hits.values().all(|presses| presses.len() == 2) {
+                let mut result = 1;
+                for (id, presses) in hits {
+                    let (first, second) = (presses[0], presses[1]);
+                    if second != 2 * first {
+                        return Err(CycleError::NotACycle {
+                            label: self.labels[id].clone(),
+                            first,
+                            second,
+                        });
+                    }
+                    result = lcm(result, first);
+                }
+                return Ok(result);
+            }
+        }
+        Err(CycleError::Timeout(max_presses))
+    }
+
+    /// Forgets every pulse the conjunctions have seen, as if all their inputs
+    /// had just sent low. `parse` already starts them this way.
+    fn reset_con_states(&mut self) {
+        for (module, inputs) in self.modules.iter_mut().zip(&self.inputs) {
+            module.connect(inputs.len());
+        }
+    }
+
+    fn get_con_inputs(&self, label: &str) -> Vec<String> {
+        match self.ids.get(label) {
+            Some(&id) => self.inputs[id]
+                .iter()
+                .map(|&input| self.labels[input].clone())
+                .collect(),
+            None => Vec::new(),
         }
-        inputs
     }
 }
 
 fn part1(input: &str) -> usize {
     let mut map = ModuleMap::from(input);
-    map.set_init_con_states();
 
     for _ in 0..1000 {
         map.push_button();
@@ -222,13 +975,34 @@ fn part1(input: &str) -> usize {
 
 fn part2(input: &str) -> usize {
     let mut map = ModuleMap::from(input);
-    map.set_init_con_states();
 
-    for _ in 0..1000 {
-        map.push_button();
+    match map.presses_until_low("rx", 1_000_000) {
+        Ok(presses) => presses,
+        Err(err) => panic!("{}", err),
     }
+}
 
-    map.high_count * map.low_count
+fn vcd_id(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        return a;
+    }
+    gcd(b, a % b)
+}
+
+fn lcm(a: usize, b: usize) -> usize {
+    a * b / gcd(a, b)
 }
 
 #[test]
@@ -250,5 +1024,328 @@ broadcaster -> a
 &con -> output
 ";
     assert_eq!(part1(input2), 11687500);
-    assert_eq!(part2(input1), 0);
+
+    let mut map = ModuleMap::from(input2);
+    map.start_trace(TraceFilter::default());
+    map.push_button();
+    let trace = map.take_trace().unwrap();
+    let lines = trace
+        .events
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            "1.0: button -Low-> broadcaster",
+            "1.1: broadcaster -Low-> a",
+            "1.2: a -High-> inv",
+            "1.3: a -High-> con",
+            "1.4: inv -Low-> b",
+            "1.5: con -High-> output",
+            "1.6: b -High-> con",
+            "1.7: con -Low-> output",
+        ]
+    );
+    let mut twice = ModuleMap::from("broadcaster -> a\n%a -> c, c\n&c -> out");
+    assert_eq!(twice.get_con_inputs("c"), vec!["a"]);
+    twice.start_trace(TraceFilter::labels(&["out"]));
+    twice.push_button();
+    let lines = twice
+        .take_trace()
+        .unwrap()
+        .events
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(lines, vec!["1.4: c -Low-> out", "1.5: c -Low-> out"]);
+
+    let filter = TraceFilter::labels(&["con"]).pulse(Pulse::High);
+    let seqs = trace.filtered(&filter).map(|e| e.seq).collect::<Vec<_>>();
+    assert_eq!(seqs, vec![3, 5, 6]);
+
+    map.start_trace(TraceFilter::labels(&["output"]));
+    for _ in 0..4 {
+        map.push_button();
+    }
+    let trace = map.take_trace().unwrap();
+    assert_eq!(trace.presses, 4);
+    assert!(trace.events.iter().all(|e| e.to == "output"));
+
+    let mut fresh = ModuleMap::from(input2);
+    fresh.push_button();
+    assert_eq!(fresh.replay(&trace), Ok(()));
+    let mut map = ModuleMap::from(input2);
+    let mut vcd = Vec::new();
+    map.write_vcd(2, &mut vcd).unwrap();
+    let vcd = String::from_utf8(vcd).unwrap();
+    assert!(vcd.starts_with("$timescale 1ns $end\n$scope module circuit $end\n"));
+    assert!(vcd.contains("$var wire 1 ! a $end\n"));
+    assert!(vcd.contains("$var wire 1 $ con $end\n"));
+    assert!(vcd.contains("$dumpvars\n0!\n0\"\n0#\n1$\n1%\n$end\n"));
+    assert!(vcd.contains("#3\n1!\n#5\n0%\n#7\n1\"\n#8\n0$\n"));
+    assert!(vcd.ends_with("#14\n1$\n#15\n"));
+    assert_eq!(map.presses, 2);
+
+    // Presses made while exporting still land in a trace that is running.
+    let mut exported = ModuleMap::from(input2);
+    exported.start_trace(TraceFilter::labels(&["con"]));
+    exported.push_button();
+    exported.write_vcd(2, &mut io::sink()).unwrap();
+    exported.push_button();
+    let with_export = exported.take_trace().unwrap();
+    let mut plain = ModuleMap::from(input2);
+    plain.start_trace(TraceFilter::labels(&["con"]));
+    for _ in 0..4 {
+        plain.push_button();
+    }
+    let without_export = plain.take_trace().unwrap();
+    assert_eq!(with_export.presses, 4);
+    assert_eq!(with_export.events, without_export.events);
+    assert_eq!(ModuleMap::from(input2).replay(&with_export), Ok(()));
+
+    assert_eq!(vcd_id(93), "~");
+    assert_eq!(vcd_id(94), "!!");
+
+    let mut map = ModuleMap::from(input2);
+    assert_eq!(
+        map.render_dot(None),
+        r#"digraph modules {
+    "a" [shape=box];
+    "b" [shape=box];
+    "broadcaster" [shape=doubleoctagon];
+    "con" [shape=diamond];
+    "inv" [shape=diamond];
+    "output" [shape=circle];
+    "a" -> "inv";
+    "a" -> "con";
+    "b" -> "con";
+    "broadcaster" -> "a";
+    "con" -> "output";
+    "inv" -> "b";
+}
+"#
+    );
+    let dot = map.render_dot(Some(1));
+    assert!(dot.contains("\"a\" [shape=box, style=filled, fillcolor=gold];"));
+    assert!(dot.contains("\"inv\" [shape=diamond, style=filled, fillcolor=lightgrey];"));
+    assert_eq!(map.presses, 1);
+
+    let mut unpressed = ModuleMap::from(input2);
+    let divergence = unpressed.replay(&trace).unwrap_err();
+    assert_ne!(divergence.expected, divergence.actual);
+
+    let counters = "
+broadcaster -> a0, b0
+%a0 -> a1, ca
+%a1 -> a2
+%a2 -> ca
+&ca -> a0, a1, ia
+&ia -> hub
+%b0 -> b1, cb
+%b1 -> b2, cb
+%b2 -> cb
+&cb -> b0, ib
+&ib -> hub
+&hub -> rx
+";
+    assert_eq!(part2(counters), 35);
+
+    let mut map = ModuleMap::from(input1);
+    assert_eq!(
+        map.presses_until_low("rx", 100),
+        Err(CycleError::NoFeeder("rx".to_string()))
+    );
+
+    let mut map = ModuleMap::from("broadcaster -> a\n%a -> rx");
+    assert_eq!(
+        map.presses_until_low("rx", 100),
+        Err(CycleError::NotConjunction("a".to_string()))
+    );
+
+    let mut map = ModuleMap::from("broadcaster -> a\n%a -> b\n%b -> hub\n&hub -> rx");
+    assert_eq!(
+        map.presses_until_low("rx", 100),
+        Err(CycleError::NotACycle {
+            label: "b".to_string(),
+            first: 2,
+            second: 6
+        })
+    );
+    assert_eq!(map.presses_until_low("rx", 1), Err(CycleError::Timeout(1)));
+
+    // ia sends high twice on press 1 before its last input arrives.
+    let mut map = ModuleMap::from(
+        "
+broadcaster -> a, b, c
+%a -> ia
+%b -> ia
+%c -> ia
+&ia -> hub
+&hub -> rx
+",
+    );
+    assert_eq!(map.presses_until_low("rx", 100), Ok(1));
+
+    let mut map = ModuleMap::from(input2);
+    let initial = map.snapshot();
+    assert_eq!(initial.len, 5);
+    map.push_button();
+    let after_one = map.snapshot();
+    assert_ne!(after_one, initial);
+    map.restore(&initial);
+    assert_eq!(map.snapshot(), initial);
+    map.push_button();
+    assert_eq!(map.snapshot(), after_one);
+    map.restore(&initial);
+    assert_eq!(
+        map.find_cycle(None, 100),
+        Some(Cycle {
+            start: 0,
+            length: 4,
+            high_count: 11,
+            low_count: 17
+        })
+    );
+
+    let mut map = ModuleMap::from(input1);
+    assert_eq!(
+        map.find_cycle(None, 100),
+        Some(Cycle {
+            start: 0,
+            length: 1,
+            high_count: 4,
+            low_count: 8
+        })
+    );
+
+    let mut map = ModuleMap::from(counters);
+    let a = ["a0", "a1", "a2", "ca"].map(String::from);
+    assert_eq!(map.find_cycle(Some(&a), 100).unwrap().length, 5);
+    assert_eq!(map.subgraph("b2"), ["b0", "b1", "b2", "cb", "hub", "ib"]);
+    let mut map = ModuleMap::from(counters);
+    assert_eq!(
+        map.find_cycle(Some(&map.subgraph("b2")), 100)
+            .unwrap()
+            .length,
+        7
+    );
+    let mut map = ModuleMap::from(counters);
+    assert_eq!(map.find_cycle(None, 100).unwrap().length, 35);
+    assert_eq!(map.find_cycle(None, 10), None);
+
+    let mut map = ModuleMap::from(counters);
+    assert_eq!(map.module_count, 12);
+    assert_eq!(map.labels[12], "rx");
+    assert_eq!(map.get_con_inputs("hub"), ["ia", "ib"]);
+    for _ in 0..35 {
+        map.push_button();
+    }
+    let settled = map.snapshot();
+    for _ in 0..35_000 {
+        map.push_button();
+    }
+    assert_eq!(map.snapshot(), settled);
+
+    let registry = ModuleRegistry::default();
+    assert_eq!(
+        ModuleMap::parse("broadcaster -> a\n?a -> b", &registry).unwrap_err(),
+        ParseError::UnknownKind("?a -> b".to_string())
+    );
+    assert_eq!(
+        ModuleMap::parse(
+            "broadcaster -> a\n%a -> b",
+            &ModuleRegistry::default().strict()
+        )
+        .unwrap_err(),
+        ParseError::Undeclared("b".to_string())
+    );
+    assert_eq!(
+        ModuleMap::parse("broadcaster -> t\n#t -> out\nout", &registry).unwrap_err(),
+        ParseError::BadArgument("t".to_string(), "counter needs a modulus".to_string())
+    );
+    assert_eq!(
+        ModuleMap::parse("broadcaster -> a\n%a -> a\n&a -> a", &registry).unwrap_err(),
+        ParseError::Duplicate("a".to_string())
+    );
+    assert_eq!(
+        ModuleMap::parse("broadcaster -> a\n%a -> out\na\nout", &registry).unwrap_err(),
+        ParseError::Duplicate("a".to_string())
+    );
+    assert_eq!(
+        ModuleMap::parse("%a -> out", &registry).unwrap_err(),
+        ParseError::NoBroadcaster
+    );
+
+    let extras = "
+broadcaster -> not, tick, hold
+!not -> out
+#tick:3 -> out
+~hold -> out
+out
+";
+    let mut map = ModuleMap::parse(extras, &ModuleRegistry::default().strict()).unwrap();
+    assert_eq!(map.module_count, 4);
+    map.start_trace(TraceFilter::labels(&["out"]));
+    for _ in 0..3 {
+        map.push_button();
+    }
+    let pulses = map
+        .take_trace()
+        .unwrap()
+        .events
+        .iter()
+        .map(|e| format!("{}{:?}", e.from, e.pulse))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        pulses,
+        vec![
+            "notHigh", "tickLow", "holdLow", "notHigh", "tickLow", "holdLow", "notHigh",
+            "tickHigh", "holdLow",
+        ]
+    );
+    assert!(map.render_dot(None).contains("\"tick\" [shape=octagon];"));
+    assert_eq!(map.snapshot().len, 1 + 1 + 2);
+
+    #[derive(Debug, Default)]
+    struct Toggle {
+        level: bool,
+    }
+    impl Behavior for Toggle {
+        fn kind(&self) -> &'static str {
+            "toggle"
+        }
+        fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+            self.level ^= high;
+            Some(self.level)
+        }
+    }
+    let mut registry = ModuleRegistry::default();
+    registry.register("^", |_| Ok(Box::<Toggle>::default()));
+    let mut map = ModuleMap::parse("broadcaster -> t\n^t -> rx", &registry).unwrap();
+    map.push_button();
+    assert_eq!(map.modules[map.ids["t"]].kind(), "toggle");
+    assert!(!map.level(map.ids["t"]));
+
+    // Only the trait methods decide what a module is, not its display name.
+    #[derive(Debug)]
+    struct Impostor(&'static str);
+    impl Behavior for Impostor {
+        fn kind(&self) -> &'static str {
+            self.0
+        }
+        fn receive(&mut self, high: bool, _: usize) -> Option<bool> {
+            Some(!high)
+        }
+    }
+    let mut registry = ModuleRegistry::default();
+    registry.register("?", |_| Ok(Box::new(Impostor("sink"))));
+    registry.register("=", |_| Ok(Box::new(Impostor("conjunction"))));
+    let mut map = ModuleMap::parse("broadcaster -> s\n?s -> c\n=c -> rx", &registry).unwrap();
+    assert_eq!(map.module_count, 3);
+    assert_eq!(map.sorted_labels(), vec!["broadcaster", "c", "s"]);
+    assert_eq!(
+        map.presses_until_low("rx", 10),
+        Err(CycleError::NotConjunction("c".to_string()))
+    );
 }
@@ -1,10 +1,18 @@
-use std::ops::RangeInclusive;
+use std::{fmt::Display, ops::RangeInclusive};
+
+use crate::interval::IntervalSet;
 
 pub fn solve() {
     let input = std::fs::read_to_string("input/day5.txt").unwrap();
     println!("# Day 5");
     println!("Part 1: {}", part1(&input));
     println!("Part 2: {}", part2(&input));
+    for problem in Mapping::from(&input[..]).validate() {
+        println!("Warning: {}", problem);
+    }
+    if std::env::args().any(|arg| arg == "--composed") {
+        print!("{}", Mapping::from(&input[..]).composed());
+    }
 }
 
 fn part1(input: &str) -> i64 {
@@ -38,7 +46,7 @@ impl Mapping {
         let ranges = self.seed_ranges();
         let mut min_location = i64::MAX;
 
-        for range in ranges {
+        for range in ranges.iter() {
             let mut seed = *range.start();
             while seed <= *range.end() {
                 let (location, skip) = self.convert_and_skip(seed);
@@ -55,11 +63,83 @@ impl Mapping {
         })
     }
 
-    fn seed_ranges(&self) -> Vec<RangeInclusive<i64>> {
-        let seeds: &[i64] = &self.seeds;
-        let mut chunks: Vec<&[i64]> = seeds.chunks(2).collect();
-        chunks.sort_by(|a, b| a[0].cmp(&b[0]));
-        chunks.iter().map(|x| x[0]..=x[0] + x[1]).collect()
+    fn convert(&self, value: i64, from: &str, to: &str) -> Option<i64> {
+        let mut value = value;
+        let mut current = from;
+        for _ in 0..=self.steps.len() {
+            if current == to {
+                return Some(value);
+            }
+            let step = self.steps.iter().find(|step| step.source == current)?;
+            value = step.source_to_destination(value);
+            current = &step.destination;
+        }
+        None
+    }
+
+    fn validate(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut expected = "seed";
+        for step in &self.steps {
+            if step.source != expected {
+                problems.push(Problem::Disconnected {
+                    from: expected.to_string(),
+                    to: step.source.clone(),
+                });
+            }
+            expected = &step.destination;
+        }
+        if expected != "location" {
+            problems.push(Problem::Unfinished {
+                end: expected.to_string(),
+            });
+        }
+        for step in &self.steps {
+            for (i, a) in step.rules.iter().enumerate() {
+                for (j, b) in step.rules.iter().enumerate().skip(i + 1) {
+                    let overlap = IntervalSet::from(a.source_range())
+                        .intersection(&IntervalSet::from(b.source_range()));
+                    if !overlap.is_empty() {
+                        problems.push(Problem::Overlap {
+                            map: step.name(),
+                            first: i,
+                            second: j,
+                        });
+                    }
+                }
+            }
+            let covered = step
+                .rules
+                .iter()
+                .map(Rule::source_range)
+                .collect::<IntervalSet<_>>();
+            if let (Some(min), Some(max)) = (covered.min(), covered.max()) {
+                for gap in IntervalSet::from(min..=max).difference(&covered).iter() {
+                    problems.push(Problem::Gap {
+                        map: step.name(),
+                        range: gap.clone(),
+                    });
+                }
+            }
+        }
+        problems
+    }
+
+    fn composed(&self) -> Piecewise {
+        self.steps.iter().fold(Piecewise::default(), |acc, step| {
+            acc.then(&Piecewise::from(step))
+        })
+    }
+
+    fn seeds_at(&self, location: i64) -> Vec<i64> {
+        self.composed().preimage(location)
+    }
+
+    fn seed_ranges(&self) -> IntervalSet<i64> {
+        self.seeds
+            .chunks(2)
+            .map(|x| x[0]..=x[0] + x[1] - 1)
+            .collect()
     }
 }
 
@@ -76,17 +156,74 @@ impl From<&str> for Mapping {
             .map(|s| s.parse::<i64>().unwrap())
             .collect::<Vec<i64>>();
 
-        let steps = iter.map(|s| Step::from(s)).collect::<Vec<Step>>();
+        let mut unordered = iter.map(Step::from).collect::<Vec<Step>>();
+        let mut steps = Vec::with_capacity(unordered.len());
+        let mut current = "seed".to_string();
+        while let Some(i) = unordered.iter().position(|step| step.source == current) {
+            let step = unordered.remove(i);
+            current = step.destination.clone();
+            steps.push(step);
+        }
+        steps.append(&mut unordered);
         Mapping { seeds, steps }
     }
 }
 
+#[derive(Debug, PartialEq)]
+enum Problem {
+    Disconnected {
+        from: String,
+        to: String,
+    },
+    Unfinished {
+        end: String,
+    },
+    Overlap {
+        map: String,
+        first: usize,
+        second: usize,
+    },
+    Gap {
+        map: String,
+        range: RangeInclusive<i64>,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Disconnected { from, to } => {
+                write!(f, "no map leads from {} to {}", from, to)
+            }
+            Problem::Unfinished { end } => {
+                write!(f, "the maps end at {} instead of location", end)
+            }
+            Problem::Overlap { map, first, second } => {
+                write!(f, "rules {} and {} of {} overlap", first, second, map)
+            }
+            Problem::Gap { map, range } => write!(
+                f,
+                "no rule of {} covers {}..={}, so it maps to itself",
+                map,
+                range.start(),
+                range.end()
+            ),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Step {
+    source: String,
+    destination: String,
     rules: Vec<Rule>,
 }
 
 impl Step {
+    fn name(&self) -> String {
+        format!("{}-to-{}", self.source, self.destination)
+    }
+
     fn source_to_destination(&self, source: i64) -> i64 {
         for rule in &self.rules {
             let result = rule.source_to_destination(source);
@@ -110,17 +247,21 @@ impl Step {
 
 impl From<&str> for Step {
     fn from(value: &str) -> Self {
-        let rules = value
-            .split(" map:")
-            .skip(1)
+        let mut parts = value.split(" map:");
+        let (source, destination) = parts.next().unwrap().trim().split_once("-to-").unwrap();
+        let rules = parts
             .next()
             .unwrap()
             .trim()
-            .split("\n")
-            .map(|s| Rule::from_str(s))
+            .split('\n')
+            .map(Rule::from_str)
             .collect::<Vec<Rule>>();
 
-        Self { rules }
+        Self {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            rules,
+        }
     }
 }
 
@@ -145,17 +286,23 @@ impl Rule {
         }
     }
 
+    fn source_range(&self) -> RangeInclusive<i64> {
+        self.source_start..=self.source_start + self.range_length - 1
+    }
+
+    fn offset(&self) -> i64 {
+        self.destination_start - self.source_start
+    }
+
     fn source_to_destination(&self, source: i64) -> i64 {
-        let range = self.source_start..=self.source_start + self.range_length;
-        if range.contains(&source) {
+        if self.source_range().contains(&source) {
             return self.destination_start + (source - self.source_start);
         }
         source
     }
 
     fn convert_and_skip(&self, source: i64) -> (i64, i64) {
-        let range = self.source_start..=self.source_start + self.range_length;
-        if range.contains(&source) {
+        if self.source_range().contains(&source) {
             return (
                 self.destination_start + (source - self.source_start),
                 self.range_length - (source - self.source_start),
@@ -165,6 +312,164 @@ impl Rule {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    source: RangeInclusive<i64>,
+    offset: i64,
+}
+
+impl Segment {
+    fn destination(&self) -> RangeInclusive<i64> {
+        shift(&self.source, self.offset)
+    }
+}
+
+fn shift(range: &RangeInclusive<i64>, offset: i64) -> RangeInclusive<i64> {
+    range.start() + offset..=range.end() + offset
+}
+
+/// A piecewise-linear map: values inside a segment are shifted by its offset,
+/// every other value maps to itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Piecewise {
+    segments: Vec<Segment>,
+}
+
+impl Piecewise {
+    fn new(mut segments: Vec<Segment>) -> Self {
+        segments.retain(|s| s.offset != 0 && !s.source.is_empty());
+        segments.sort_by_key(|s| *s.source.start());
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments {
+            match merged.last_mut() {
+                Some(last)
+                    if last.offset == segment.offset
+                        && last.source.end() + 1 == *segment.source.start() =>
+                {
+                    last.source = *last.source.start()..=*segment.source.end();
+                }
+                _ => merged.push(segment),
+            }
+        }
+        Piecewise { segments: merged }
+    }
+
+    fn apply(&self, value: i64) -> i64 {
+        self.segments
+            .iter()
+            .find(|s| s.source.contains(&value))
+            .map_or(value, |s| value + s.offset)
+    }
+
+    fn domain(&self) -> IntervalSet<i64> {
+        self.segments.iter().map(|s| s.source.clone()).collect()
+    }
+
+    fn image(&self) -> IntervalSet<i64> {
+        self.segments.iter().map(|s| s.destination()).collect()
+    }
+
+    /// Applies `self` first and `next` to the result.
+    fn then(&self, next: &Piecewise) -> Piecewise {
+        let mut segments = Vec::new();
+        for segment in &self.segments {
+            let mut rest = IntervalSet::from(segment.destination());
+            for other in &next.segments {
+                let hit = rest.intersection(&IntervalSet::from(other.source.clone()));
+                for range in hit.iter() {
+                    segments.push(Segment {
+                        source: shift(range, -segment.offset),
+                        offset: segment.offset + other.offset,
+                    });
+                }
+                rest = rest.difference(&hit);
+            }
+            for range in rest.iter() {
+                segments.push(Segment {
+                    source: shift(range, -segment.offset),
+                    offset: segment.offset,
+                });
+            }
+        }
+        let domain = self.domain();
+        for other in &next.segments {
+            let gaps = IntervalSet::from(other.source.clone()).difference(&domain);
+            for range in gaps.iter() {
+                segments.push(Segment {
+                    source: range.clone(),
+                    offset: other.offset,
+                });
+            }
+        }
+        Piecewise::new(segments)
+    }
+
+    fn preimage(&self, value: i64) -> Vec<i64> {
+        let mut result = self
+            .segments
+            .iter()
+            .filter(|s| s.destination().contains(&value))
+            .map(|s| value - s.offset)
+            .collect::<Vec<_>>();
+        if !self.domain().contains(&value) {
+            result.push(value);
+        }
+        result.sort();
+        result
+    }
+
+    /// Returns the inverse map, or `None` when some value has more than one source.
+    fn inverse(&self) -> Option<Piecewise> {
+        let image = self.image();
+        let image_len: usize = self.segments.iter().map(|s| s.source.clone().count()).sum();
+        if image.len() != image_len || image != self.domain() {
+            return None;
+        }
+        let segments = self
+            .segments
+            .iter()
+            .map(|s| Segment {
+                source: s.destination(),
+                offset: -s.offset,
+            })
+            .collect();
+        Some(Piecewise::new(segments))
+    }
+}
+
+impl From<&Step> for Piecewise {
+    fn from(step: &Step) -> Self {
+        let mut covered = IntervalSet::new();
+        let mut segments = Vec::new();
+        for rule in &step.rules {
+            let source = IntervalSet::from(rule.source_range()).difference(&covered);
+            for range in source.iter() {
+                segments.push(Segment {
+                    source: range.clone(),
+                    offset: rule.offset(),
+                });
+            }
+            covered = covered.union(&source);
+        }
+        Piecewise::new(segments)
+    }
+}
+
+impl Display for Piecewise {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for segment in &self.segments {
+            writeln!(
+                f,
+                "{} {} {}",
+                segment.destination().start(),
+                segment.source.start(),
+                segment.source.end() - segment.source.start() + 1
+            )?;
+        }
+        Ok(())
+    }
+}
+
 #[test]
 fn test() {
     let input = "
@@ -205,4 +510,103 @@ humidity-to-location map:
 
     assert_eq!(part1(&input), 35);
     assert_eq!(part2(&input), 46);
+
+    let mapping = Mapping::from(input);
+    let composed = mapping.composed();
+    for seed in 0..200 {
+        let location = mapping
+            .steps
+            .iter()
+            .fold(seed, |acc, step| step.source_to_destination(acc));
+        assert_eq!(composed.apply(seed), location);
+        assert!(mapping.seeds_at(location).contains(&seed));
+    }
+    assert_eq!(mapping.seeds_at(82), vec![79]);
+    assert_eq!(mapping.seeds_at(46), vec![82]);
+
+    let inverse = composed.inverse().unwrap();
+    for seed in 0..200 {
+        assert_eq!(inverse.apply(composed.apply(seed)), seed);
+    }
+    assert_eq!(composed.then(&inverse), Piecewise::default());
+
+    let table = composed.to_string();
+    let reparsed = Piecewise::from(&Step::from(
+        &format!("seed-to-location map:\n{}", table)[..],
+    ));
+    assert_eq!(reparsed, composed);
+
+    let collapsing = Step::from("a-to-b map:\n0 10 5\n0 20 5");
+    assert_eq!(Piecewise::from(&collapsing).inverse(), None);
+
+    assert_eq!(mapping.convert(79, "seed", "soil"), Some(81));
+    assert_eq!(mapping.convert(79, "seed", "humidity"), Some(78));
+    assert_eq!(mapping.convert(78, "humidity", "location"), Some(82));
+    assert_eq!(mapping.convert(79, "seed", "seed"), Some(79));
+    assert_eq!(mapping.convert(79, "soil", "seed"), None);
+    assert!(mapping.validate().is_empty());
+
+    let shuffled = "
+seeds: 79 14
+
+water-to-location map:
+10 0 5
+
+seed-to-soil map:
+50 98 2
+52 50 48
+52 40 20
+
+soil-to-water map:
+0 15 37
+";
+    let mapping = Mapping::from(shuffled);
+    let names = mapping.steps.iter().map(Step::name).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["seed-to-soil", "soil-to-water", "water-to-location"]
+    );
+    assert_eq!(
+        mapping.validate(),
+        vec![Problem::Overlap {
+            map: "seed-to-soil".to_string(),
+            first: 1,
+            second: 2
+        }]
+    );
+
+    let broken =
+        Mapping::from("seeds: 1\n\nseed-to-soil map:\n0 0 1\n\nwater-to-location map:\n0 0 1");
+    assert_eq!(
+        broken.validate(),
+        vec![Problem::Disconnected {
+            from: "soil".to_string(),
+            to: "water".to_string()
+        }]
+    );
+    assert_eq!(broken.convert(0, "seed", "location"), None);
+
+    let short = Mapping::from("seeds: 1\n\nseed-to-soil map:\n0 0 1");
+    assert_eq!(
+        short.validate(),
+        vec![Problem::Unfinished {
+            end: "soil".to_string()
+        }]
+    );
+
+    let gapped = Mapping::from("seeds: 1\n\nseed-to-location map:\n0 10 5\n20 20 5\n5 30 2");
+    assert_eq!(
+        gapped.validate(),
+        vec![
+            Problem::Gap {
+                map: "seed-to-location".to_string(),
+                range: 15..=19
+            },
+            Problem::Gap {
+                map: "seed-to-location".to_string(),
+                range: 25..=29
+            }
+        ]
+    );
+    assert_eq!(gapped.composed().apply(17), 17);
 }
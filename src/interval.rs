@@ -0,0 +1,344 @@
+use std::ops::RangeInclusive;
+
+pub trait Discrete: Copy + Ord {
+    fn succ(self) -> Self;
+    fn pred(self) -> Self;
+    fn span(start: Self, end: Self) -> usize;
+}
+
+macro_rules! impl_discrete {
+    ($($t:ty),*) => {
+        $(
+            impl Discrete for $t {
+                fn succ(self) -> Self {
+                    self + 1
+                }
+                fn pred(self) -> Self {
+                    self - 1
+                }
+                fn span(start: Self, end: Self) -> usize {
+                    if end < start {
+                        0
+                    } else {
+                        (end as i128 - start as i128 + 1) as usize
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_discrete!(i32, i64, isize, u32, u64, usize);
+
+/// A set of integers stored as sorted, disjoint and non-adjacent inclusive ranges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntervalSet<T> {
+    ranges: Vec<RangeInclusive<T>>,
+}
+
+impl<T> Default for IntervalSet<T> {
+    fn default() -> Self {
+        IntervalSet { ranges: Vec::new() }
+    }
+}
+
+impl<T: Discrete> IntervalSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_range(range: RangeInclusive<T>) -> Self {
+        let mut set = Self::new();
+        set.insert(range);
+        set
+    }
+
+    pub fn insert(&mut self, range: RangeInclusive<T>) {
+        if range.is_empty() {
+            return;
+        }
+        let (mut start, mut end) = range.into_inner();
+        let mut result = Vec::with_capacity(self.ranges.len() + 1);
+        let mut placed = false;
+        for r in self.ranges.drain(..) {
+            if *r.end() < start && r.end().succ() < start {
+                result.push(r);
+            } else if *r.start() > end && *r.start() > end.succ() {
+                if !placed {
+                    result.push(start..=end);
+                    placed = true;
+                }
+                result.push(r);
+            } else {
+                start = start.min(*r.start());
+                end = end.max(*r.end());
+            }
+        }
+        if !placed {
+            result.push(start..=end);
+        }
+        self.ranges = result;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|r| T::span(*r.start(), *r.end()))
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RangeInclusive<T>> {
+        self.ranges.iter()
+    }
+
+    pub fn min(&self) -> Option<T> {
+        self.ranges.first().map(|r| *r.start())
+    }
+
+    pub fn max(&self) -> Option<T> {
+        self.ranges.last().map(|r| *r.end())
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.ranges.iter().any(|r| r.contains(value))
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        for range in other.iter() {
+            result.insert(range.clone());
+        }
+        result
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (a, b) = (&self.ranges[i], &other.ranges[j]);
+            let start = *a.start().max(b.start());
+            let end = *a.end().min(b.end());
+            if start <= end {
+                ranges.push(start..=end);
+            }
+            if a.end() < b.end() {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        IntervalSet { ranges }
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut ranges = Vec::new();
+        for range in &self.ranges {
+            let mut start = *range.start();
+            let end = *range.end();
+            let mut done = false;
+            for cut in other.iter() {
+                if *cut.end() < start {
+                    continue;
+                }
+                if *cut.start() > end {
+                    break;
+                }
+                if *cut.start() > start {
+                    ranges.push(start..=cut.start().pred());
+                }
+                if *cut.end() >= end {
+                    done = true;
+                    break;
+                }
+                start = cut.end().succ();
+            }
+            if !done {
+                ranges.push(start..=end);
+            }
+        }
+        IntervalSet { ranges }
+    }
+
+    /// Splits the set into the values below `at` and the values at or above it.
+    pub fn split_at(&self, at: T) -> (Self, Self) {
+        let mut below = Vec::new();
+        let mut above = Vec::new();
+        for range in &self.ranges {
+            if *range.start() < at {
+                below.push(*range.start()..=(*range.end()).min(at.pred()));
+            }
+            if *range.end() >= at {
+                above.push((*range.start()).max(at)..=*range.end());
+            }
+        }
+        (IntervalSet { ranges: below }, IntervalSet { ranges: above })
+    }
+}
+
+impl<T: Discrete> FromIterator<RangeInclusive<T>> for IntervalSet<T> {
+    fn from_iter<I: IntoIterator<Item = RangeInclusive<T>>>(iter: I) -> Self {
+        let mut set = Self::new();
+        for range in iter {
+            set.insert(range);
+        }
+        set
+    }
+}
+
+impl<T: Discrete> From<RangeInclusive<T>> for IntervalSet<T> {
+    fn from(range: RangeInclusive<T>) -> Self {
+        Self::from_range(range)
+    }
+}
+
+/// An axis-aligned box with one non-empty inclusive range per dimension.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IntervalBox<T> {
+    dims: Vec<RangeInclusive<T>>,
+}
+
+impl<T: Discrete> IntervalBox<T> {
+    pub fn new(dims: Vec<RangeInclusive<T>>) -> Option<Self> {
+        if dims.iter().any(|r| r.is_empty()) {
+            return None;
+        }
+        Some(IntervalBox { dims })
+    }
+
+    pub fn uniform(range: RangeInclusive<T>, count: usize) -> Option<Self> {
+        Self::new(vec![range; count])
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims.len()
+    }
+
+    pub fn get(&self, dim: usize) -> &RangeInclusive<T> {
+        &self.dims[dim]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RangeInclusive<T>> {
+        self.dims.iter()
+    }
+
+    pub fn volume(&self) -> usize {
+        self.dims
+            .iter()
+            .map(|r| T::span(*r.start(), *r.end()))
+            .product()
+    }
+
+    pub fn contains(&self, point: &[T]) -> bool {
+        point.len() == self.dims.len() && self.dims.iter().zip(point).all(|(r, v)| r.contains(v))
+    }
+
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        let dims = self
+            .dims
+            .iter()
+            .zip(&other.dims)
+            .map(|(a, b)| *a.start().max(b.start())..=*a.end().min(b.end()))
+            .collect();
+        Self::new(dims)
+    }
+
+    /// Splits along `dim` into the part below `at` and the part at or above it.
+    pub fn split_at(&self, dim: usize, at: T) -> (Option<Self>, Option<Self>) {
+        let range = &self.dims[dim];
+        let below = (*range.start() < at).then(|| {
+            let mut below = self.clone();
+            below.dims[dim] = *range.start()..=(*range.end()).min(at.pred());
+            below
+        });
+        let above = (*range.end() >= at).then(|| {
+            let mut above = self.clone();
+            above.dims[dim] = (*range.start()).max(at)..=*range.end();
+            above
+        });
+        (below, above)
+    }
+}
+
+#[test]
+fn test_insert_merges() {
+    let set: IntervalSet<i64> = [5..=8, 1..=3, 4..=4, 12..=15, 10..=10]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        set.iter().cloned().collect::<Vec<_>>(),
+        vec![1..=8, 10..=10, 12..=15]
+    );
+    assert_eq!(set.len(), 13);
+    assert_eq!(set.min(), Some(1));
+    assert_eq!(set.max(), Some(15));
+    assert!(set.contains(&10));
+    assert!(!set.contains(&11));
+
+    let mut set = set;
+    set.insert(9..=11);
+    assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec![1..=15]);
+    let empty = 20;
+    set.insert(empty..=empty - 1);
+    assert_eq!(set.len(), 15);
+}
+
+#[test]
+fn test_set_operations() {
+    let a: IntervalSet<usize> = [1..=10, 20..=30].into_iter().collect();
+    let b: IntervalSet<usize> = [5..=25, 28..=40].into_iter().collect();
+
+    let union = a.union(&b);
+    assert_eq!(union.iter().cloned().collect::<Vec<_>>(), vec![1..=40]);
+
+    let intersection = a.intersection(&b);
+    assert_eq!(
+        intersection.iter().cloned().collect::<Vec<_>>(),
+        vec![5..=10, 20..=25, 28..=30]
+    );
+
+    let difference = a.difference(&b);
+    assert_eq!(
+        difference.iter().cloned().collect::<Vec<_>>(),
+        vec![1..=4, 26..=27]
+    );
+    assert_eq!(b.difference(&a).len(), 9 + 10);
+    assert!(a.difference(&a).is_empty());
+    assert_eq!(a.difference(&IntervalSet::new()), a);
+
+    let (below, above) = a.split_at(21);
+    assert_eq!(
+        below.iter().cloned().collect::<Vec<_>>(),
+        vec![1..=10, 20..=20]
+    );
+    assert_eq!(above.iter().cloned().collect::<Vec<_>>(), vec![21..=30]);
+    let (below, above) = a.split_at(0);
+    assert!(below.is_empty());
+    assert_eq!(above, a);
+}
+
+#[test]
+fn test_box() {
+    let b = IntervalBox::uniform(1..=4000usize, 4).unwrap();
+    assert_eq!(b.volume(), 4000usize.pow(4));
+
+    let (below, above) = b.split_at(2, 1001);
+    let (below, above) = (below.unwrap(), above.unwrap());
+    assert_eq!(below.get(2), &(1..=1000));
+    assert_eq!(above.get(2), &(1001..=4000));
+    assert_eq!(below.volume() + above.volume(), b.volume());
+    assert!(below.contains(&[1, 4000, 1000, 1]));
+    assert!(!below.contains(&[1, 4000, 1001, 1]));
+
+    let (none, all) = b.split_at(0, 1);
+    assert!(none.is_none());
+    assert_eq!(all, Some(b.clone()));
+
+    assert_eq!(below.intersection(&above), None);
+    assert_eq!(below.intersection(&b), Some(below));
+    let empty = 3;
+    assert_eq!(IntervalBox::new(vec![1..=2, empty..=empty - 1]), None);
+}
@@ -21,6 +21,8 @@ pub mod day7;
 pub mod day8;
 pub mod day9;
 pub mod grid2d;
+pub mod interval;
+pub mod polygon;
 
 fn main() {
     day20::solve();
@@ -0,0 +1,196 @@
+/// A point on the integer lattice.
+pub type Point = (isize, isize);
+
+/// Which way a loop runs on the puzzle grid, where y grows downwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Clockwise,
+    CounterClockwise,
+    /// The loop encloses no area.
+    Degenerate,
+}
+
+/// Area, boundary and interior lattice points of a closed lattice polygon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measure {
+    pub area: i128,
+    /// Lattice points on the edges, which is the perimeter when every edge is
+    /// horizontal or vertical.
+    pub boundary: i128,
+    pub interior: i128,
+    pub orientation: Orientation,
+}
+
+impl Measure {
+    /// Lattice points on or inside the polygon.
+    pub fn enclosed(&self) -> i128 {
+        self.interior + self.boundary
+    }
+}
+
+/// Twice the signed area of the loop `points`, positive when it runs
+/// clockwise on the grid. The last point joins the first. `None` if the sum
+/// overflows.
+pub fn shoelace(points: &[Point]) -> Option<i128> {
+    edges(points).try_fold(0i128, |sum, ((x1, y1), (x2, y2))| {
+        let term = (x1 as i128 * y2 as i128).checked_sub(x2 as i128 * y1 as i128)?;
+        sum.checked_add(term)
+    })
+}
+
+pub fn boundary_length(points: &[Point]) -> Option<i128> {
+    edges(points).try_fold(0i128, |sum, ((x1, y1), (x2, y2))| {
+        let dx = (x2 as i128 - x1 as i128).abs();
+        let dy = (y2 as i128 - y1 as i128).abs();
+        sum.checked_add(gcd(dx, dy))
+    })
+}
+
+/// Measures a vertex loop with the shoelace formula and Pick's theorem,
+/// `A = I + B/2 - 1`. Points along a straight edge may be listed or left out.
+/// `None` if the loop is too large to measure in `i128`.
+pub fn measure(points: &[Point]) -> Option<Measure> {
+    let signed = shoelace(points)?;
+    let orientation = match signed.signum() {
+        1 => Orientation::Clockwise,
+        -1 => Orientation::CounterClockwise,
+        _ => Orientation::Degenerate,
+    };
+    let doubled = signed.checked_abs()?;
+    let boundary = boundary_length(points)?;
+    Some(Measure {
+        area: doubled / 2,
+        boundary,
+        interior: (doubled - boundary + 2) / 2,
+        orientation,
+    })
+}
+
+/// Pairs of edges that cross, touch or overlap anywhere other than the
+/// corner shared by neighbouring edges. Edge `i` runs from `points[i]` to the
+/// next point; edges of length zero are ignored.
+pub fn self_intersections(points: &[Point]) -> Vec<(usize, usize)> {
+    let edges = edges(points)
+        .enumerate()
+        .filter(|(_, (a, b))| a != b)
+        .collect::<Vec<_>>();
+    let mut result = Vec::new();
+    for (i, &(first, (a, b))) in edges.iter().enumerate() {
+        for (j, &(second, (c, d))) in edges.iter().enumerate().skip(i + 1) {
+            let adjacent = j == i + 1 || (i == 0 && j == edges.len() - 1);
+            let crossing = if adjacent {
+                // Neighbours only meet at their shared corner unless one
+                // turns straight back along the other.
+                let (p, q, r) = if j == i + 1 { (a, b, d) } else { (c, d, b) };
+                cross(p, q, r) == 0 && dot(p, q, r) < 0
+            } else {
+                segments_touch(a, b, c, d)
+            };
+            if crossing {
+                result.push((first, second));
+            }
+        }
+    }
+    result
+}
+
+/// The z component of `(q - p) x (r - p)`, in `i128` so that products of
+/// coordinate differences cannot overflow for realistic plans.
+fn cross(p: Point, q: Point, r: Point) -> i128 {
+    let d = |a: isize, b: isize| b as i128 - a as i128;
+    d(p.0, q.0) * d(p.1, r.1) - d(p.1, q.1) * d(p.0, r.0)
+}
+
+/// `(q - p) . (r - q)`, negative when the path p, q, r turns back on itself.
+fn dot(p: Point, q: Point, r: Point) -> i128 {
+    let d = |a: isize, b: isize| b as i128 - a as i128;
+    d(p.0, q.0) * d(q.0, r.0) + d(p.1, q.1) * d(q.1, r.1)
+}
+
+fn within(a: Point, b: Point, p: Point) -> bool {
+    a.0.min(b.0) <= p.0 && p.0 <= a.0.max(b.0) && a.1.min(b.1) <= p.1 && p.1 <= a.1.max(b.1)
+}
+
+fn segments_touch(a: Point, b: Point, c: Point, d: Point) -> bool {
+    let (d1, d2) = (cross(a, b, c).signum(), cross(a, b, d).signum());
+    let (d3, d4) = (cross(c, d, a).signum(), cross(c, d, b).signum());
+    if d1 * d2 < 0 && d3 * d4 < 0 {
+        return true;
+    }
+    (d1 == 0 && within(a, b, c))
+        || (d2 == 0 && within(a, b, d))
+        || (d3 == 0 && within(c, d, a))
+        || (d4 == 0 && within(c, d, b))
+}
+
+fn edges(points: &[Point]) -> impl Iterator<Item = (Point, Point)> + '_ {
+    points
+        .iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| (*a, *b))
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn test_measure() {
+    let square = [(0, 0), (4, 0), (4, 4), (0, 4)];
+    assert_eq!(shoelace(&square), Some(32));
+    let reversed = square.iter().rev().cloned().collect::<Vec<_>>();
+    assert_eq!(shoelace(&reversed), Some(-32));
+    let measured = measure(&square).unwrap();
+    assert_eq!(
+        measured,
+        Measure {
+            area: 16,
+            boundary: 16,
+            interior: 9,
+            orientation: Orientation::Clockwise
+        }
+    );
+    assert_eq!(measured.enclosed(), 25);
+    assert_eq!(
+        measure(&reversed).unwrap().orientation,
+        Orientation::CounterClockwise
+    );
+    assert_eq!(
+        measure(&[(0, 0), (3, 0)]).unwrap().orientation,
+        Orientation::Degenerate
+    );
+
+    let with_midpoints = [(0, 0), (2, 0), (4, 0), (4, 4), (0, 4), (0, 2)];
+    assert_eq!(measure(&with_midpoints), measure(&square));
+
+    let triangle = [(0, 0), (4, 0), (0, 4)];
+    assert_eq!(boundary_length(&triangle), Some(12));
+    let measured = measure(&triangle).unwrap();
+    assert_eq!((measured.area, measured.interior), (8, 3));
+
+    // Far beyond what isize could hold, but still exact.
+    let big = 1isize << 40;
+    let huge = [(0, 0), (big, 0), (big, big), (0, big)];
+    assert_eq!(measure(&huge).unwrap().area, 1i128 << 80);
+    let max = isize::MAX;
+    let overflowing = [(max, -max), (max, max), (-max, max), (-max, -max)];
+    assert_eq!(shoelace(&overflowing), None);
+    assert_eq!(measure(&overflowing), None);
+}
+
+#[test]
+fn test_self_intersections() {
+    let square = [(0, 0), (4, 0), (4, 4), (0, 4)];
+    assert!(self_intersections(&square).is_empty());
+    let bowtie = [(0, 0), (4, 4), (4, 0), (0, 4)];
+    assert_eq!(self_intersections(&bowtie), vec![(0, 2)]);
+    // Edges 3 and 4 meet in the middle of edge 0.
+    let touching = [(0, 0), (4, 0), (4, 2), (2, 2), (2, 0), (2, -2), (0, -2)];
+    assert_eq!(self_intersections(&touching), vec![(0, 3), (0, 4)]);
+    let back = [(0, 0), (3, 0), (1, 0), (1, 2)];
+    assert_eq!(self_intersections(&back), vec![(0, 1), (0, 2)]);
+}